@@ -4,4 +4,23 @@ use std::path::PathBuf;
 pub struct Args {
     #[arg(default_value = "./config.toml", short, long)]
     pub config: PathBuf,
-}
\ No newline at end of file
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Show and apply pending diary database migrations
+    Migrate {
+        /// Only show pending migrations
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Grant or revoke the diary admin role
+    Admin {
+        username: String,
+        /// Revoke the role instead
+        #[arg(long)]
+        revoke: bool,
+    },
+}
@@ -63,6 +63,65 @@ pub struct SomeToolsAppConfig {
 #[serde(rename_all = "kebab-case")]
 pub struct DiaryConfig {
     pub database_file: String,
+    /// number of read-only database connections
+    pub database_readers: Option<usize>,
+    /// directory of attachment files; defaults to `attachments` next to
+    /// the database file
+    pub attachment_dir: Option<String>,
+    /// max size of an attachment in bytes
+    pub max_attachment_size: Option<u64>,
+    /// allowed MIME types of attachments; `type/*` matches all subtypes
+    pub attachment_types: Option<Vec<String>>,
+    /// max size of import requests in bytes
+    pub max_import_size: Option<usize>,
+    /// usernames granted the admin role on startup
+    pub admins: Option<Vec<String>>,
+    /// if true, modifying existing diary entries, books and profiles
+    /// requires `If-Match`
+    pub require_if_match: Option<bool>,
+    #[serde(default)]
+    pub signup: SignupConfig,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignupMode {
+    /// anyone can sign up
+    Open,
+    /// signup requires an invite code generated by an admin
+    Invite,
+    Closed,
+}
+
+/// Registration controls of the diary service
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct SignupConfig {
+    pub mode: SignupMode,
+    /// in characters
+    pub min_password_length: usize,
+    /// max signup attempts per IP address in `attempt-window`; 0 disables
+    /// the limit
+    pub max_attempts: u32,
+    /// in seconds
+    pub attempt_window: u64,
+    /// header carrying the client IP address when behind a reverse proxy,
+    /// e.g. `X-Real-IP`; the peer address is used if absent or invalid. If
+    /// the header holds a comma-separated list as `X-Forwarded-For` does,
+    /// the last address is used, i.e. the one appended by the proxy.
+    pub ip_header: Option<String>,
+}
+
+impl Default for SignupConfig {
+    fn default() -> Self {
+        Self {
+            mode: SignupMode::Open,
+            min_password_length: 8,
+            max_attempts: 10,
+            attempt_window: 60 * 60,
+            ip_header: None,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Default)]
@@ -71,10 +130,77 @@ pub struct ServerConfig {
     pub port: u16,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct JwtConfig {
+    /// id of the key used for signing new tokens; other keys are only
+    /// used for verification, e.g. during key rotation
+    pub signing_key: Option<String>,
+    /// if empty, a random key is generated on every start
+    #[serde(default)]
+    pub keys: Vec<JwtKeyConfig>,
+    /// `iss` claim; defaults to [`DEFAULT_JWT_ISSUER`]
+    pub issuer: Option<String>,
+    /// `aud` claim; defaults to [`DEFAULT_JWT_ISSUER`]
+    pub audience: Option<String>,
+    /// allowed clock skew in seconds for `exp` validation
+    pub leeway: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct JwtKeyConfig {
+    /// written to the `kid` JWT header
+    pub id: String,
+    pub algorithm: jsonwebtoken::Algorithm,
+    /// raw secret for HMAC algorithms
+    pub secret_file: Option<String>,
+    /// PEM private key for asymmetric algorithms; only needed for signing
+    pub private_key_file: Option<String>,
+    /// PEM public key for asymmetric algorithms
+    pub public_key_file: Option<String>,
+}
+
+pub const DEFAULT_JWT_ISSUER: &str = "web-app";
+
+#[derive(Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// Attributes of cookies set by the server
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct CookieConfig {
+    pub http_only: bool,
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub path: String,
+    pub domain: Option<String>,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            http_only: true,
+            secure: true,
+            same_site: SameSite::Lax,
+            path: String::from("/"),
+            domain: None,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Default)]
 pub struct Config {
     pub app: AppConfig,
     pub server: ServerConfig,
+    pub jwt: Option<JwtConfig>,
+    #[serde(default)]
+    pub cookie: CookieConfig,
 }
 
 pub fn read_config(config_path: impl AsRef<Path>) -> anyhow::Result<Config> {
@@ -6,6 +6,7 @@ use anyhow::anyhow;
 use axum::Router;
 use clap::Parser;
 
+use web_app::cli::Command;
 use web_app::{mutex_lock, read_config, CONFIG};
 
 #[tokio::main]
@@ -18,23 +19,34 @@ async fn main() -> anyhow::Result<()> {
             args.config.display()
         ));
     }
-    let config = read_config(args.config)?;
+    let config = read_config(&args.config)?;
     println!("Config: {:?}", config);
 
     *CONFIG.lock().unwrap() = config;
 
+    match args.command {
+        Some(Command::Migrate { dry_run }) => {
+            return web_app::routes::diary::migration::run_command(dry_run);
+        }
+        Some(Command::Admin { username, revoke }) => {
+            return web_app::routes::diary::admin::run_command(&username, !revoke);
+        }
+        None => {}
+    }
+
     start().await?;
     Ok(())
 }
 
-fn initialize() {
-    web_app::security::init();
+fn initialize() -> anyhow::Result<()> {
+    web_app::security::init()?;
     web_app::routes::diary::init();
     web_app::routes::system_info::start_update_thread();
+    Ok(())
 }
 
 async fn start() -> anyhow::Result<()> {
-    initialize();
+    initialize()?;
 
     let (addr, port) = {
         let guard = mutex_lock!(CONFIG);
@@ -54,7 +66,7 @@ async fn start() -> anyhow::Result<()> {
     let addr = SocketAddr::new(addr.parse()?, port);
     println!("Server started on {}", addr);
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await?;
     Ok(())
 }
@@ -72,6 +84,7 @@ fn router() -> Router {
         app,
         demo,
         diary,
+        jwt_keys,
         server_network_log,
         ccit_info,
         random,
@@ -1,10 +1,13 @@
-use crate::routes::demo::authentication::{jwt_secret, JwtClaims};
-use axum::headers::{Header, HeaderValue, SetCookie};
+use crate::routes::demo::authentication::JwtClaims;
+use crate::security::{build_cookie, encode_jwt, set_cookies};
+use axum::headers::SetCookie;
 use axum::response::IntoResponse;
 use axum::{Form, Json, TypedHeader};
-use jsonwebtoken::{Algorithm, EncodingKey};
 use serde::{Deserialize, Serialize};
 
+/// 1h
+const TOKEN_LIFETIME: u64 = 3600;
+
 #[derive(Deserialize)]
 pub struct Input {
     username: String,
@@ -53,9 +56,9 @@ fn create_response(r#type: ResponseType) -> (TypedHeader<SetCookie>, Json<Respon
         .map(|x| String::from(&x.token))
         .unwrap_or(String::default());
 
-    let values = [HeaderValue::from_str(&format!("token={}", token)).unwrap()];
-
-    let header = TypedHeader(SetCookie::decode(&mut values.iter()).unwrap());
+    // an empty token clears the cookie
+    let max_age = if token.is_empty() { 0 } else { TOKEN_LIFETIME };
+    let header = set_cookies([build_cookie("token", &token, max_age)]);
     (header, Json(data))
 }
 
@@ -69,17 +72,13 @@ pub async fn authenticate(form: Option<Form<Input>>) -> impl IntoResponse {
         return create_response(ResponseType::WrongPassword);
     }
 
-    let jwt_secret = jwt_secret();
-
-    let header = jsonwebtoken::Header::new(Algorithm::HS512);
     let issued_at = jsonwebtoken::get_current_timestamp();
     let claim = JwtClaims {
         iat: issued_at,
-        exp: issued_at + 3600, /* 1h */
+        exp: issued_at + TOKEN_LIFETIME,
         username: form.username.clone(),
     };
-    let jwt =
-        jsonwebtoken::encode(&header, &claim, &EncodingKey::from_secret(&jwt_secret)).unwrap();
+    let jwt = encode_jwt(&claim);
 
     create_response(ResponseType::Success { jwt })
 }
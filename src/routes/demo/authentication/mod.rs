@@ -1,11 +1,7 @@
 use axum::routing::{get, post};
 use axum::Router;
-use rsa::pkcs8::EncodePrivateKey;
 use serde::{Deserialize, Serialize};
 
-use crate::mutex_lock;
-use crate::security::JWT_SECRET;
-
 pub mod login;
 pub mod request;
 
@@ -18,10 +14,6 @@ pub(crate) struct JwtClaims {
     exp: u64,
 }
 
-pub(crate) fn jwt_secret() -> Vec<u8> {
-    mutex_lock!(JWT_SECRET).unwrap().into()
-}
-
 pub fn router() -> Router {
     Router::new()
         .route("/login", post(login::authenticate))
@@ -0,0 +1,304 @@
+//! User management by administrators
+//!
+//! The admin role is granted by the `admins` config or the `admin` CLI
+//! subcommand; disabled administrators lose their privileges.
+
+use anyhow::anyhow;
+use axum::extract::Path;
+use axum::response::IntoResponse;
+use axum::Form;
+use axum_extra::extract::CookieJar;
+use serde::{Deserialize, Serialize};
+
+use crate::routes::diary::database;
+use crate::routes::diary::database::Pool;
+use crate::routes::diary::signup::{generate_invite_code, is_strong_password, SIGNUP_CONFIG};
+use crate::routes::diary::{failure_response, generate_password_hash, timestamp, ResponseStatus};
+use crate::{get_session, mutex_lock, ResponseJson, CONFIG};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSummary {
+    pub id: u64,
+    pub username: String,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub signup_time: u64,
+    pub is_admin: bool,
+    pub disabled: bool,
+}
+
+/// Data in diary books owned by a user; sizes are in bytes
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageUsage {
+    pub user_id: u64,
+    pub username: String,
+    pub book_count: u32,
+    pub entry_count: u32,
+    pub content_size: u64,
+    pub revision_size: u64,
+    pub attachment_size: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResetPasswordForm {
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InviteCodeForm {
+    /// in seconds; never expires if absent
+    pub lifetime: Option<u64>,
+}
+
+/// Early-returns [`ResponseStatus::PermissionDenied`] from the database
+/// closure unless the session user is an administrator
+macro_rules! require_admin {
+    ($db:expr, $user_id:expr) => {
+        if !$db.is_admin($user_id)? {
+            return Ok(failure_response(ResponseStatus::PermissionDenied).into_response());
+        }
+    };
+}
+
+pub async fn list_users(cookies: CookieJar) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        require_admin!(db, claims.user_id);
+
+        let users = db.list_users()?;
+        Ok(ResponseJson::ok(users).into_response())
+    })
+    .await
+    .into_response()
+}
+
+pub async fn storage_usage(cookies: CookieJar) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        require_admin!(db, claims.user_id);
+
+        let usage = db.query_storage_usage()?;
+        Ok(ResponseJson::ok(usage).into_response())
+    })
+    .await
+    .into_response()
+}
+
+async fn set_disabled(cookies: CookieJar, username: String, disabled: bool) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::write(move |db| {
+        require_admin!(db, claims.user_id);
+        let Some(user_id) = db.query_user_id(&username)? else {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        };
+        // so that at least one administrator is left
+        if user_id == claims.user_id {
+            return Ok(failure_response(ResponseStatus::PermissionDenied).into_response());
+        }
+
+        db.set_user_disabled(user_id, disabled)?;
+        if disabled {
+            db.delete_user_sessions(user_id)?;
+        }
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
+}
+
+/// Disables the account and revokes all its sessions
+pub async fn disable_user(cookies: CookieJar, Path(username): Path<String>) -> impl IntoResponse {
+    set_disabled(cookies, username, true).await
+}
+
+pub async fn enable_user(cookies: CookieJar, Path(username): Path<String>) -> impl IntoResponse {
+    set_disabled(cookies, username, false).await
+}
+
+/// Sets a new password for the user, e.g. when they forget it; all their
+/// sessions and personal access tokens are revoked. Passwords of
+/// administrators can't be reset this way.
+pub async fn reset_password(
+    cookies: CookieJar,
+    Path(username): Path<String>,
+    Form(form): Form<ResetPasswordForm>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    let min_length = SIGNUP_CONFIG.min_password_length;
+    if !is_strong_password(&form.new_password, &username, min_length) {
+        return failure_response(ResponseStatus::WeakPassword).into_response();
+    }
+    let is_admin = database::read(move |db| Ok(db.is_admin(claims.user_id)?)).await;
+    match is_admin {
+        Ok(true) => {}
+        Ok(false) => return failure_response(ResponseStatus::PermissionDenied).into_response(),
+        Err(e) => return e.into_response(),
+    }
+    let pw_hash = generate_password_hash(form.new_password).await;
+
+    database::write(move |db| {
+        let Some(user_id) = db.query_user_id(&username)? else {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        };
+        if db.has_admin_role(user_id)? {
+            return Ok(failure_response(ResponseStatus::PermissionDenied).into_response());
+        }
+
+        db.update_password_hash(user_id, &pw_hash)?;
+        db.delete_user_sessions(user_id)?;
+        db.delete_user_api_tokens(user_id)?;
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
+}
+
+pub async fn list_invite_codes(cookies: CookieJar) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        require_admin!(db, claims.user_id);
+
+        let codes = db.list_invite_codes()?;
+        Ok(ResponseJson::ok(codes).into_response())
+    })
+    .await
+    .into_response()
+}
+
+/// Generates a single-use invite code
+pub async fn create_invite_code(
+    cookies: CookieJar,
+    Form(form): Form<InviteCodeForm>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::write(move |db| {
+        require_admin!(db, claims.user_id);
+
+        let code = generate_invite_code();
+        let expiration_time = form.lifetime.map(|x| timestamp().saturating_add(x));
+        db.add_invite_code(&code, claims.user_id, expiration_time)?;
+        Ok(ResponseJson::ok(code).into_response())
+    })
+    .await
+    .into_response()
+}
+
+pub async fn delete_invite_code(cookies: CookieJar, Path(code): Path<String>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::write(move |db| {
+        require_admin!(db, claims.user_id);
+
+        if !db.delete_invite_code(&code)? {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        }
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
+}
+
+/// Entry for the `admin` CLI subcommand
+pub fn run_command(username: &str, admin: bool) -> anyhow::Result<()> {
+    let database_file = mutex_lock!(CONFIG)
+        .app
+        .diary
+        .as_ref()
+        .ok_or_else(|| anyhow!("Missing diary config"))?
+        .database_file
+        .clone();
+    let pool = Pool::open(database_file, 1)?;
+    if !pool.set_admin(username, admin)? {
+        return Err(anyhow!("User doesn't exist: {}", username));
+    }
+    match admin {
+        true => println!("Granted the admin role to {}", username),
+        false => println!("Revoked the admin role from {}", username),
+    }
+    Ok(())
+}
+
+#[test]
+fn storage_usage_aggregation() {
+    use crate::routes::diary::database::{Database, MetadataUpdate};
+
+    let db = Database::open_in_memory();
+    let owner = db.add_user("owner", "").unwrap();
+    let other = db.add_user("other", "").unwrap();
+    let book_id = db.create_diary_book("book", owner, false).unwrap();
+    db.create_diary_book("empty", owner, false).unwrap();
+    let metadata = MetadataUpdate::default();
+    let diary_id = db
+        .update_diary(book_id, 20230415, "今天", None, &metadata)
+        .unwrap();
+    db.update_diary(book_id, 20230415, "today", None, &metadata)
+        .unwrap();
+    db.update_diary(book_id, 20230416, "abc", None, &metadata)
+        .unwrap();
+    db.add_attachment(diary_id, "hash", "a.png", "image/png", 100)
+        .unwrap();
+    db.add_attachment(diary_id, "hash", "b.png", "image/png", 100)
+        .unwrap();
+
+    let usage = db.query_storage_usage().unwrap();
+    assert_eq!(usage.len(), 2);
+    assert_eq!(usage[0].user_id, owner);
+    assert_eq!(usage[0].book_count, 2);
+    assert_eq!(usage[0].entry_count, 2);
+    assert_eq!(usage[0].content_size, 8);
+    // the replaced content, in UTF-8 bytes
+    assert_eq!(usage[0].revision_size, 6);
+    assert_eq!(usage[0].attachment_size, 200);
+    assert_eq!(usage[1].user_id, other);
+    assert_eq!(usage[1].book_count, 0);
+    assert_eq!(usage[1].content_size, 0);
+}
+
+#[test]
+fn disabled_sessions() {
+    use crate::routes::diary::api_token::TokenScope;
+    use crate::routes::diary::database::Database;
+
+    let db = Database::open_in_memory();
+    let user_id = db.add_user("user", "").unwrap();
+    let session_id = db
+        .create_session(user_id, "refresh", timestamp() + 60)
+        .unwrap();
+    assert!(db.check_session(session_id, user_id).unwrap());
+    assert!(db
+        .query_session_by_refresh_token("refresh")
+        .unwrap()
+        .is_some());
+
+    db.set_user_disabled(user_id, true).unwrap();
+    assert!(db.is_user_disabled(user_id).unwrap());
+    assert!(!db.check_session(session_id, user_id).unwrap());
+    assert!(db
+        .query_session_by_refresh_token("refresh")
+        .unwrap()
+        .is_none());
+
+    // disabled administrators keep the role, so their passwords can't be reset
+    db.set_admin("user", true).unwrap();
+    assert!(!db.is_admin(user_id).unwrap());
+    assert!(db.has_admin_role(user_id).unwrap());
+
+    db.set_user_disabled(user_id, false).unwrap();
+    assert!(db.check_session(session_id, user_id).unwrap());
+    assert!(db.is_admin(user_id).unwrap());
+
+    db.add_api_token(user_id, "script", "hash", TokenScope::ReadOnly, None)
+        .unwrap();
+    db.delete_user_api_tokens(user_id).unwrap();
+    assert!(db.list_api_tokens(user_id).unwrap().is_empty());
+}
@@ -0,0 +1,332 @@
+//! Personal access tokens for scripted access
+//!
+//! Users create named tokens that are either read-only or read-write, and
+//! send them as `Authorization: Bearer <token>`. Requests authenticated with
+//! a token act as the user; read-only tokens are limited to GET and HEAD
+//! requests, however the token is sent. Tokens can't manage tokens or
+//! two-factor authentication.
+
+use axum::extract::Path;
+use axum::http::{header, HeaderValue, Method, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Form;
+use axum_extra::extract::CookieJar;
+use hex::ToHex;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::routes::diary::database;
+use crate::routes::diary::{failure_response, timestamp, JwtClaims, ResponseStatus};
+use crate::{get_session, ResponseJson};
+
+/// Distinguishes personal access tokens from JWTs
+const TOKEN_PREFIX: &str = "dpat_";
+/// in characters
+const MAX_NAME_LENGTH: usize = 64;
+/// Last used times are updated at most once in this many seconds
+const LAST_USED_RESOLUTION: u64 = 60;
+
+tokio::task_local! {
+    /// Method of the request being handled, set by [`bearer_auth`]
+    static REQUEST_METHOD: Method;
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenScope {
+    ReadOnly,
+    ReadWrite,
+}
+
+impl TokenScope {
+    pub fn from_db_int(scope: u8) -> TokenScope {
+        match scope {
+            1 => TokenScope::ReadWrite,
+            // the least privilege for unknown values
+            _ => TokenScope::ReadOnly,
+        }
+    }
+
+    pub fn to_db_int(self) -> u8 {
+        match self {
+            TokenScope::ReadOnly => 0,
+            TokenScope::ReadWrite => 1,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiToken {
+    pub id: u64,
+    pub name: String,
+    pub scope: TokenScope,
+    pub creation_time: u64,
+    pub expiration_time: Option<u64>,
+    pub last_used_time: Option<u64>,
+}
+
+/// A valid token along with its owner
+pub(crate) struct TokenOwner {
+    pub token: ApiToken,
+    pub user_id: u64,
+    pub username: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateForm {
+    pub name: String,
+    pub scope: TokenScope,
+    /// in seconds; never expires if absent
+    pub lifetime: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedToken {
+    pub id: u64,
+    /// only shown this once
+    pub token: String,
+}
+
+fn generate_api_token() -> String {
+    let mut token = [0_u8; 32];
+    OsRng.fill_bytes(&mut token);
+    format!("{}{}", TOKEN_PREFIX, token.encode_hex::<String>())
+}
+
+/// Tokens are random enough, so a fast hash is sufficient
+fn hash_api_token(token: &str) -> String {
+    blake3::hash(token.as_bytes()).to_hex().to_string()
+}
+
+pub(crate) fn is_api_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+/// Whether a token of `scope` may be used for requests of `method`
+fn allows_method(scope: TokenScope, method: &Method) -> bool {
+    scope == TokenScope::ReadWrite || method == Method::GET || method == Method::HEAD
+}
+
+/// Checks the scope of the personal access token the request is
+/// authenticated with, if any, against the request method. Outside of
+/// [`bearer_auth`] the method is unknown, and only read-write tokens pass.
+pub(crate) fn check_scope(claims: &JwtClaims) -> Result<(), ResponseStatus> {
+    let Some(scope) = claims.api_token_scope else {
+        return Ok(());
+    };
+    let allowed = REQUEST_METHOD
+        .try_with(|x| allows_method(scope, x))
+        .unwrap_or(scope == TokenScope::ReadWrite);
+    match allowed {
+        true => Ok(()),
+        false => Err(ResponseStatus::PermissionDenied),
+    }
+}
+
+/// Resolves a personal access token to the claims of its owner; the session
+/// id is 0, as no session is involved
+pub(crate) async fn resolve_api_token(token: &str) -> database::Result<Option<JwtClaims>> {
+    let hash = hash_api_token(token);
+    let now = timestamp();
+    let Some(owner) = database::read(move |db| Ok(db.query_api_token(&hash, now)?)).await? else {
+        return Ok(None);
+    };
+
+    let id = owner.token.id;
+    let last_used_time = owner.token.last_used_time.unwrap_or(0);
+    if now.saturating_sub(last_used_time) >= LAST_USED_RESOLUTION {
+        database::write(move |db| Ok(db.update_api_token_last_used(id, now)?)).await?;
+    }
+    Ok(Some(JwtClaims {
+        username: owner.username,
+        user_id: owner.user_id,
+        sid: 0,
+        iat: owner.token.creation_time,
+        exp: owner.token.expiration_time.unwrap_or(u64::MAX),
+        api_token: Some(id),
+        api_token_scope: Some(owner.token.scope),
+    }))
+}
+
+/// Builds a `Cookie` header value out of `headers`, with any `token` cookie
+/// replaced by `token`
+fn replace_token_cookie<'a>(headers: impl IntoIterator<Item = &'a str>, token: &str) -> String {
+    headers
+        .into_iter()
+        .flat_map(|x| x.split(';'))
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && x.split('=').next() != Some("token"))
+        .chain([format!("token={}", token).as_str()])
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Middleware passing `Authorization: Bearer` tokens on as the `token`
+/// cookie, so that they're resolved like access tokens; an explicit header
+/// takes precedence over an existing cookie. It also provides the request
+/// method to [`check_scope`].
+pub(crate) async fn bearer_auth<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let method = request.method().clone();
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .map(|x| x.trim().to_string());
+    if let Some(token) = token {
+        let headers = request.headers_mut();
+        let cookies = headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .collect::<Vec<_>>();
+        // tokens are hex or base64url, which are valid in cookies
+        if let Ok(cookie) = HeaderValue::from_str(&replace_token_cookie(cookies, &token)) {
+            headers.insert(header::COOKIE, cookie);
+        }
+    }
+    REQUEST_METHOD.scope(method, next.run(request)).await
+}
+
+/// Account and session management needs a login session; personal access
+/// tokens are rejected
+pub(crate) fn check_login_session(claims: &JwtClaims) -> Result<(), ResponseStatus> {
+    match claims.api_token {
+        Some(_) => Err(ResponseStatus::PermissionDenied),
+        None => Ok(()),
+    }
+}
+
+/// Early-returns [`ResponseStatus::PermissionDenied`] for requests
+/// authenticated with a personal access token
+macro_rules! require_login_session {
+    ($claims:expr) => {
+        if let Err(status) = crate::routes::diary::api_token::check_login_session(&$claims) {
+            return failure_response(status).into_response();
+        }
+    };
+}
+pub(crate) use require_login_session;
+
+pub async fn list(cookies: CookieJar) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+    require_login_session!(claims);
+
+    database::read(move |db| {
+        let tokens = db.list_api_tokens(claims.user_id)?;
+        Ok(ResponseJson::ok(tokens).into_response())
+    })
+    .await
+    .into_response()
+}
+
+pub async fn create(cookies: CookieJar, Form(form): Form<CreateForm>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+    require_login_session!(claims);
+
+    let name = form.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return failure_response(ResponseStatus::InvalidTokenName).into_response();
+    }
+
+    let token = generate_api_token();
+    let hash = hash_api_token(&token);
+    let expiration_time = form.lifetime.map(|x| timestamp().saturating_add(x));
+    database::write(move |db| {
+        let id = db.add_api_token(claims.user_id, &name, &hash, form.scope, expiration_time)?;
+        Ok(ResponseJson::ok(CreatedToken { id, token }).into_response())
+    })
+    .await
+    .into_response()
+}
+
+pub async fn revoke(cookies: CookieJar, Path(id): Path<u64>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+    require_login_session!(claims);
+
+    database::write(move |db| {
+        if !db.delete_api_token(id, claims.user_id)? {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        }
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
+}
+
+#[test]
+fn api_tokens() {
+    let token = generate_api_token();
+    assert!(is_api_token(&token));
+    assert_eq!(token.len(), TOKEN_PREFIX.len() + 64);
+    assert!(!is_api_token("eyJ0eXAiOiJKV1QiLCJhbGciOiJIUzI1NiJ9"));
+    assert_ne!(
+        hash_api_token(&token),
+        hash_api_token(&generate_api_token())
+    );
+
+    assert!(allows_method(TokenScope::ReadOnly, &Method::GET));
+    assert!(allows_method(TokenScope::ReadOnly, &Method::HEAD));
+    assert!(!allows_method(TokenScope::ReadOnly, &Method::PUT));
+    assert!(allows_method(TokenScope::ReadWrite, &Method::DELETE));
+    assert_eq!(TokenScope::from_db_int(7), TokenScope::ReadOnly);
+
+    // bearer tokens take precedence over the cookie
+    assert_eq!(
+        replace_token_cookie(["token=jwt; theme=dark", "a=1"], "dpat_x"),
+        "theme=dark; a=1; token=dpat_x"
+    );
+    assert_eq!(replace_token_cookie([], "dpat_x"), "token=dpat_x");
+}
+
+#[tokio::test]
+async fn token_scopes() {
+    let claims = |scope: Option<TokenScope>| JwtClaims {
+        username: String::from("user"),
+        user_id: 1,
+        sid: 0,
+        iat: 0,
+        exp: u64::MAX,
+        api_token: scope.map(|_| 1),
+        api_token_scope: scope,
+    };
+    let (login, read_only, read_write) = (
+        claims(None),
+        claims(Some(TokenScope::ReadOnly)),
+        claims(Some(TokenScope::ReadWrite)),
+    );
+
+    // no matter whether the token came as a cookie or a bearer token
+    REQUEST_METHOD
+        .scope(Method::PUT, async {
+            assert_eq!(
+                check_scope(&read_only),
+                Err(ResponseStatus::PermissionDenied)
+            );
+            assert_eq!(check_scope(&read_write), Ok(()));
+            assert_eq!(check_scope(&login), Ok(()));
+        })
+        .await;
+    REQUEST_METHOD
+        .scope(Method::GET, async {
+            assert_eq!(check_scope(&read_only), Ok(()));
+        })
+        .await;
+    // unknown methods
+    assert_eq!(
+        check_scope(&read_only),
+        Err(ResponseStatus::PermissionDenied)
+    );
+    assert_eq!(check_scope(&read_write), Ok(()));
+
+    // even read-write tokens can't manage the account or sessions
+    assert_eq!(check_login_session(&login), Ok(()));
+    assert_eq!(
+        check_login_session(&read_write),
+        Err(ResponseStatus::PermissionDenied)
+    );
+}
@@ -0,0 +1,478 @@
+//! Files attached to diary entries
+//!
+//! Contents are stored as `<attachment dir>/<first two hex digits>/<hash>`,
+//! where the hash is the hex-encoded BLAKE3 hash of the content, so identical
+//! files are stored only once. Files no longer referenced by any attachment
+//! are removed after deletions.
+
+use std::io;
+use std::io::SeekFrom;
+use std::path::{Path as FsPath, PathBuf};
+
+use axum::body::StreamBody;
+use axum::extract::{Multipart, Path};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum_extra::extract::CookieJar;
+use hex::ToHex;
+use once_cell::sync::Lazy;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+use crate::routes::diary::database;
+use crate::routes::diary::database::Database;
+use crate::routes::diary::sharing::{check_role, Role};
+use crate::routes::diary::{failure_response, ResponseStatus};
+use crate::{get_session, mutex_lock, ResponseJson, CONFIG};
+
+/// 10 MiB
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_TYPES: [&str; 5] = [
+    "image/*",
+    "audio/*",
+    "video/*",
+    "application/pdf",
+    "text/plain",
+];
+/// SVG images can contain scripts, so `image/*` doesn't cover them
+const SCRIPTABLE_IMAGE_TYPE: &str = "image/svg+xml";
+/// Max number of files in one upload
+const MAX_UPLOAD_FILES: usize = 16;
+/// in characters
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
+struct AttachmentConfig {
+    dir: PathBuf,
+    max_size: u64,
+    types: Vec<String>,
+}
+
+static ATTACHMENT_CONFIG: Lazy<AttachmentConfig> = Lazy::new(|| {
+    let guard = mutex_lock!(CONFIG);
+    let config = guard.app.diary.as_ref().expect("Missing config");
+    let dir = match &config.attachment_dir {
+        Some(x) => PathBuf::from(x),
+        None => FsPath::new(&config.database_file)
+            .parent()
+            .unwrap_or(FsPath::new("."))
+            .join("attachments"),
+    };
+    AttachmentConfig {
+        dir,
+        max_size: config.max_attachment_size.unwrap_or(DEFAULT_MAX_SIZE),
+        types: match &config.attachment_types {
+            Some(x) => x.iter().map(|x| x.to_ascii_lowercase()).collect(),
+            None => DEFAULT_TYPES.iter().map(|x| x.to_string()).collect(),
+        },
+    }
+});
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Attachment {
+    pub id: u64,
+    pub diary_id: u64,
+    pub file_name: String,
+    pub mime_type: String,
+    /// in bytes
+    pub size: u64,
+    /// hex-encoded BLAKE3 hash
+    pub hash: String,
+    pub creation_time: u64,
+}
+
+/// A received file in the temporary directory, which is removed on drop
+/// unless it's been moved into the storage
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+struct Upload {
+    file: TempFile,
+    hash: String,
+    size: u64,
+    file_name: String,
+    mime_type: String,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum ByteRange {
+    Full,
+    /// inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+fn file_path(hash: &str) -> PathBuf {
+    ATTACHMENT_CONFIG.dir.join(&hash[..2]).join(hash)
+}
+
+/// `allowed` are lowercase patterns; `type/*` matches all subtypes except
+/// [`SCRIPTABLE_IMAGE_TYPE`], which has to be listed explicitly
+fn is_allowed_type(allowed: &[String], mime_type: &str) -> bool {
+    let Some((main_type, _)) = mime_type.split_once('/') else {
+        return false;
+    };
+    allowed.iter().any(|x| match x.strip_suffix("/*") {
+        Some(x) => x == main_type && mime_type != SCRIPTABLE_IMAGE_TYPE,
+        None => x == mime_type,
+    })
+}
+
+/// Whether browsers can display files of the type without running scripts
+/// on this origin; others are served as downloads
+fn is_inline_type(mime_type: &str) -> bool {
+    let Some((main_type, _)) = mime_type.split_once('/') else {
+        return false;
+    };
+    match main_type {
+        "image" => mime_type != SCRIPTABLE_IMAGE_TYPE,
+        "audio" | "video" => true,
+        _ => mime_type == "text/plain",
+    }
+}
+
+/// Parses a `Range` header; only single ranges are supported, and others
+/// are ignored, i.e. the whole content is returned
+fn parse_range(header: Option<&str>, size: u64) -> ByteRange {
+    let Some(spec) = header.and_then(|x| x.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return ByteRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // the last `end` bytes
+        return match end.parse::<u64>() {
+            Err(_) => ByteRange::Full,
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if size == 0 => ByteRange::Unsatisfiable,
+            Ok(n) => ByteRange::Partial(size.saturating_sub(n), size - 1),
+        };
+    }
+    let Ok(start) = start.parse::<u64>() else {
+        return ByteRange::Full;
+    };
+    let end = match end {
+        "" => u64::MAX,
+        x => match x.parse::<u64>() {
+            Ok(x) if x >= start => x,
+            _ => return ByteRange::Full,
+        },
+    };
+    if start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end.min(size - 1))
+}
+
+/// Keeps only the last path component, in case clients send paths
+fn sanitize_file_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    match name {
+        "" => String::from("untitled"),
+        x => x.chars().take(MAX_FILE_NAME_LENGTH).collect(),
+    }
+}
+
+/// Receives a multipart field into a temporary file, hashing it meanwhile
+async fn receive_file(
+    field: &mut axum::extract::multipart::Field<'_>,
+) -> io::Result<Result<Upload, ResponseStatus>> {
+    let config = &*ATTACHMENT_CONFIG;
+    let mime_type = field
+        .content_type()
+        .unwrap_or("application/octet-stream")
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if !is_allowed_type(&config.types, &mime_type) {
+        return Ok(Err(ResponseStatus::UnsupportedAttachmentType));
+    }
+    let file_name = sanitize_file_name(field.file_name().unwrap_or_default());
+
+    let temp_dir = config.dir.join("tmp");
+    tokio::fs::create_dir_all(&temp_dir).await?;
+    let mut name = [0_u8; 16];
+    OsRng.fill_bytes(&mut name);
+    let temp = TempFile(temp_dir.join(name.encode_hex::<String>()));
+    let mut file = tokio::fs::File::create(&temp.0).await?;
+
+    let mut hasher = blake3::Hasher::new();
+    let mut size = 0_u64;
+    loop {
+        let chunk = match field.chunk().await {
+            Ok(Some(x)) => x,
+            Ok(None) => break,
+            Err(_) => return Ok(Err(ResponseStatus::InvalidAttachment)),
+        };
+        size += chunk.len() as u64;
+        if size > config.max_size {
+            return Ok(Err(ResponseStatus::AttachmentTooLarge));
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+
+    Ok(Ok(Upload {
+        file: temp,
+        hash: hasher.finalize().to_hex().to_string(),
+        size,
+        file_name,
+        mime_type,
+    }))
+}
+
+/// Moves the received file into the storage unless the same content
+/// exists; this runs with the write connection, so it can't race with
+/// [`remove_orphan_files`]
+fn store_file(upload: &Upload) -> io::Result<()> {
+    let path = file_path(&upload.hash);
+    if path.exists() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::rename(&upload.file.0, &path)
+}
+
+/// Removes files no longer referenced by any attachment; to be called
+/// with the write connection after deleting entries or attachments
+pub(crate) fn remove_orphan_files(db: &Database) -> database::Result<()> {
+    for hash in db.take_orphan_hashes()? {
+        if let Err(e) = std::fs::remove_file(file_path(&hash)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                eprintln!("Failed to remove attachment {}: {}", hash, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Uploads files in `multipart/form-data`; each file part becomes an
+/// attachment of the entry
+pub async fn upload(
+    cookies: CookieJar,
+    Path(id): Path<u64>,
+    mut multipart: Multipart,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    let user_id = claims.user_id;
+    let role = database::read(move |db| Ok(db.query_diary_role(id, user_id)?)).await;
+    match role.map(|x| check_role(x, Role::Editor)) {
+        Ok(Ok(_)) => {}
+        Ok(Err(status)) => return failure_response(status).into_response(),
+        Err(e) => return e.into_response(),
+    }
+
+    let mut uploads = Vec::new();
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(x)) => x,
+            Ok(None) => break,
+            Err(_) => return failure_response(ResponseStatus::InvalidAttachment).into_response(),
+        };
+        if field.file_name().is_none() {
+            continue;
+        }
+        if uploads.len() == MAX_UPLOAD_FILES {
+            return failure_response(ResponseStatus::InvalidAttachment).into_response();
+        }
+        match receive_file(&mut field).await {
+            Ok(Ok(x)) => uploads.push(x),
+            Ok(Err(status)) => return failure_response(status).into_response(),
+            Err(e) => return database::Error::from(e).into_response(),
+        }
+    }
+    if uploads.is_empty() {
+        return failure_response(ResponseStatus::InvalidAttachment).into_response();
+    }
+
+    database::write(move |db| {
+        // the role may have changed during the upload
+        if let Err(status) = check_role(db.query_diary_role(id, claims.user_id)?, Role::Editor) {
+            return Ok(failure_response(status).into_response());
+        }
+
+        let mut attachments = Vec::new();
+        for upload in &uploads {
+            store_file(upload)?;
+            attachments.push(db.add_attachment(
+                id,
+                &upload.hash,
+                &upload.file_name,
+                &upload.mime_type,
+                upload.size,
+            )?);
+        }
+        Ok(ResponseJson::ok(attachments).into_response())
+    })
+    .await
+    .into_response()
+}
+
+pub async fn list(cookies: CookieJar, Path(id): Path<u64>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        if let Err(status) = check_role(db.query_diary_role(id, claims.user_id)?, Role::Viewer) {
+            return Ok(failure_response(status).into_response());
+        }
+
+        let attachments = db.list_attachments(id)?;
+        Ok(ResponseJson::ok(attachments).into_response())
+    })
+    .await
+    .into_response()
+}
+
+/// Streams the content of an attachment; single byte ranges are supported
+pub async fn download(
+    cookies: CookieJar,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    let attachment = database::read(move |db| {
+        let Some(attachment) = db.query_attachment(id)? else {
+            return Ok(None);
+        };
+        let role = db.query_diary_role(attachment.diary_id, claims.user_id)?;
+        Ok(check_role(role, Role::Viewer).ok().map(|_| attachment))
+    })
+    .await;
+    let attachment = match attachment {
+        Ok(Some(x)) => x,
+        Ok(None) => return failure_response(ResponseStatus::NoRecord).into_response(),
+        Err(e) => return e.into_response(),
+    };
+
+    let range = parse_range(
+        headers.get(header::RANGE).and_then(|x| x.to_str().ok()),
+        attachment.size,
+    );
+    let mut file = match tokio::fs::File::open(file_path(&attachment.hash)).await {
+        Ok(x) => x,
+        Err(e) => return database::Error::from(e).into_response(),
+    };
+    // types are declared by uploaders, so anything else is neither sniffed
+    // nor rendered by browsers
+    let (content_type, disposition) = match is_inline_type(&attachment.mime_type) {
+        true => (attachment.mime_type, "inline"),
+        false => (String::from("application/octet-stream"), "attachment"),
+    };
+    let common_headers = [
+        (header::CONTENT_TYPE, content_type),
+        (header::X_CONTENT_TYPE_OPTIONS, String::from("nosniff")),
+        (header::ACCEPT_RANGES, String::from("bytes")),
+        (header::ETAG, format!("\"{}\"", attachment.hash)),
+        (
+            header::CONTENT_DISPOSITION,
+            format!(
+                "{}; filename*=UTF-8''{}",
+                disposition,
+                urlencoding::encode(&attachment.file_name)
+            ),
+        ),
+    ];
+
+    match range {
+        ByteRange::Full => (
+            common_headers,
+            [(header::CONTENT_LENGTH, attachment.size.to_string())],
+            StreamBody::new(ReaderStream::new(file)),
+        )
+            .into_response(),
+        ByteRange::Partial(start, end) => {
+            if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+                return database::Error::from(e).into_response();
+            }
+            let length = end - start + 1;
+            (
+                StatusCode::PARTIAL_CONTENT,
+                common_headers,
+                [
+                    (header::CONTENT_LENGTH, length.to_string()),
+                    (
+                        header::CONTENT_RANGE,
+                        format!("bytes {}-{}/{}", start, end, attachment.size),
+                    ),
+                ],
+                StreamBody::new(ReaderStream::new(file.take(length))),
+            )
+                .into_response()
+        }
+        ByteRange::Unsatisfiable => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(
+                header::CONTENT_RANGE,
+                format!("bytes */{}", attachment.size),
+            )],
+        )
+            .into_response(),
+    }
+}
+
+pub async fn delete(cookies: CookieJar, Path(id): Path<u64>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::write(move |db| {
+        let Some(attachment) = db.query_attachment(id)? else {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        };
+        let role = db.query_diary_role(attachment.diary_id, claims.user_id)?;
+        if let Err(status) = check_role(role, Role::Editor) {
+            return Ok(failure_response(status).into_response());
+        }
+
+        db.delete_attachment(id)?;
+        remove_orphan_files(db)?;
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
+}
+
+#[test]
+fn byte_ranges() {
+    use ByteRange::*;
+
+    assert_eq!(parse_range(None, 100), Full);
+    assert_eq!(parse_range(Some("bytes=0-9"), 100), Partial(0, 9));
+    assert_eq!(parse_range(Some("bytes=90-"), 100), Partial(90, 99));
+    assert_eq!(parse_range(Some("bytes=90-200"), 100), Partial(90, 99));
+    assert_eq!(parse_range(Some("bytes=-10"), 100), Partial(90, 99));
+    assert_eq!(parse_range(Some("bytes=-200"), 100), Partial(0, 99));
+    assert_eq!(parse_range(Some("bytes=100-"), 100), Unsatisfiable);
+    assert_eq!(parse_range(Some("bytes=-0"), 100), Unsatisfiable);
+    assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), Full);
+    assert_eq!(parse_range(Some("bytes=9-0"), 100), Full);
+    assert_eq!(parse_range(Some("items=0-9"), 100), Full);
+
+    let types = ["image/*".into(), "text/plain".into()];
+    assert!(is_allowed_type(&types, "image/png"));
+    assert!(is_allowed_type(&types, "text/plain"));
+    assert!(!is_allowed_type(&types, "text/html"));
+    assert!(!is_allowed_type(&types, "image"));
+    assert!(!is_allowed_type(&types, "image/svg+xml"));
+    assert!(is_allowed_type(&["image/svg+xml".into()], "image/svg+xml"));
+
+    assert!(is_inline_type("image/png"));
+    assert!(is_inline_type("text/plain"));
+    assert!(!is_inline_type("image/svg+xml"));
+    assert!(!is_inline_type("text/html"));
+    assert!(!is_inline_type("application/pdf"));
+}
@@ -1,16 +1,215 @@
-use crate::routes::diary::timestamp;
-use crate::routes::diary::user::{Gender, UserProfile};
-use crate::security::hash_password;
-use rusqlite::{params, Connection};
-use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use rusqlite::types::{Type, Value};
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::mutex_lock;
+use crate::routes::diary::admin::{StorageUsage, UserSummary};
+use crate::routes::diary::api_token::{ApiToken, TokenOwner, TokenScope};
+use crate::routes::diary::attachment::Attachment;
+use crate::routes::diary::diary_book::DiaryBook;
+use crate::routes::diary::diary_entry::{
+    highlight_snippet, DiaryEntry, SearchHit, SNIPPET_MARK_END, SNIPPET_MARK_START,
+};
+use crate::routes::diary::encryption::{Encryption, UserKey};
+use crate::routes::diary::export::{BookExport, EntryExport, ImportReport};
+use crate::routes::diary::migration;
+use crate::routes::diary::revision::{Revision, RevisionInfo};
+use crate::routes::diary::sharing::{Invitation, Member, Role};
+use crate::routes::diary::signup::InviteCode;
+use crate::routes::diary::sync::{
+    resolve, AppliedChange, Resolution, SlotState, SyncChange, SyncConflict, SyncOutcome, Tombstone,
+};
+use crate::routes::diary::tag::TagCount;
+use crate::routes::diary::two_factor::TotpSecret;
+use crate::routes::diary::user::{Gender, UserProfile};
+use crate::routes::diary::{failure_response, timestamp, ResponseStatus, DATABASE};
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Max length of search result snippets, in tokens (trigrams) or characters
+const SNIPPET_LENGTH: u32 = 64;
+/// Columns read by [`Database::entry_from_row`]; tags are aggregated
+/// into a JSON array. `m` is the book-entry mapping.
+const ENTRY_COLUMNS: &str = "d.id, d.content, d.creation_time, d.key_id, d.nonce, d.algorithm,
+       d.mood, d.location, d.updated_time,
+       (SELECT json_group_array(name)
+        FROM (SELECT t.name
+              FROM diary_tag dt
+                       INNER JOIN tag t ON dt.tag_id = t.id
+              WHERE dt.diary_id = d.id
+              ORDER BY t.name)),
+       m.book_id, m.date, d.version";
+
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+/// Errors from the database layer, including file operations done
+/// along with it; all of them are treated as server internal errors
+#[derive(Debug)]
+pub(crate) enum Error {
+    Sqlite(rusqlite::Error),
+    Task(tokio::task::JoinError),
+    Io(std::io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Sqlite(e) => write!(f, "SQLite error: {}", e),
+            Error::Task(e) => write!(f, "Task error: {}", e),
+            Error::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::Sqlite(value)
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(value: tokio::task::JoinError) -> Self {
+        Self::Task(value)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        eprintln!("Database error: {}", self);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            failure_response(ResponseStatus::InternalError),
+        )
+            .into_response()
+    }
+}
+
+/// A connection pool in WAL mode: one writer and multiple readers
+pub(crate) struct Pool {
+    writer: Mutex<Database>,
+    readers: Vec<Mutex<Database>>,
+    next_reader: AtomicUsize,
+}
+
+impl Pool {
+    pub fn open<P: AsRef<Path>>(path: P, reader_count: usize) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        // the writer runs migrations and switches to WAL mode before readers are opened
+        let writer = Database::open_writer(path)?;
+        let readers = (0..reader_count.max(1))
+            .map(|_| Database::open_reader(path).map(Mutex::new))
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Self {
+            writer: Mutex::new(writer),
+            readers,
+            next_reader: AtomicUsize::new(0),
+        })
+    }
+
+    fn reader(&self) -> MutexGuard<'_, Database> {
+        // prefer an idle connection
+        for r in &self.readers {
+            if let Ok(guard) = r.try_lock() {
+                return guard;
+            }
+        }
+        let index = self.next_reader.fetch_add(1, Ordering::Relaxed) % self.readers.len();
+        mutex_lock!(self.readers[index])
+    }
+
+    fn writer(&self) -> MutexGuard<'_, Database> {
+        mutex_lock!(self.writer)
+    }
 
-/// TODO: return SQLite error and treat as a "server internal error"
+    /// For use outside of request handlers, e.g. on startup
+    ///
+    /// Returns: false if the user doesn't exist
+    pub fn set_admin(&self, username: &str, admin: bool) -> rusqlite::Result<bool> {
+        self.writer().set_admin(username, admin)
+    }
+}
+
+/// Runs `f` with a read-only connection on the blocking thread pool
+pub(crate) async fn read<F, R>(f: F) -> Result<R>
+where
+    F: FnOnce(&Database) -> Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f(&DATABASE.reader())).await?
+}
+
+/// Runs `f` with the write connection on the blocking thread pool
+pub(crate) async fn write<F, R>(f: F) -> Result<R>
+where
+    F: FnOnce(&Database) -> Result<R> + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f(&DATABASE.writer())).await?
+}
 
 pub(crate) struct Database {
     conn: Connection,
 }
 
+pub(crate) struct Session {
+    pub id: u64,
+    pub user_id: u64,
+    pub username: String,
+    pub expiration_time: u64,
+}
+
+pub(crate) struct Credential {
+    pub user_id: u64,
+    /// PHC string, or a hex-encoded BLAKE3 hash for legacy users
+    pub password_hash: String,
+    /// only used by legacy password hashes
+    pub password_salt: String,
+}
+
+pub(crate) struct SearchFilter {
+    /// an FTS5 query expression
+    pub match_query: Option<String>,
+    /// `LIKE` patterns for terms the full-text index can't match
+    pub like_patterns: Vec<String>,
+    pub book_id: Option<u64>,
+    /// inclusive date range
+    pub from: u32,
+    pub to: u32,
+    /// markers around matched text in snippets, inserted after escaping
+    pub mark_start: String,
+    pub mark_end: String,
+}
+
+/// Changes to the metadata of an entry; `None` fields are left unchanged
+#[derive(Default)]
+pub(crate) struct MetadataUpdate {
+    pub tags: Option<Vec<String>>,
+    pub mood: Option<Option<u8>>,
+    pub location: Option<Option<String>>,
+}
+
+/// Filters of entry listing; `None` fields match all entries
+#[derive(Default)]
+pub(crate) struct EntryFilter {
+    pub tag: Option<String>,
+    pub mood: Option<u8>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct DatabaseInfo {
@@ -18,140 +217,1820 @@ pub(crate) struct DatabaseInfo {
 }
 
 impl Database {
-    pub fn new<P: AsRef<Path>>(path: P) -> rusqlite::Result<Self> {
-        let conn = Connection::open(path)?;
+    fn open_writer(path: &Path) -> anyhow::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |r| r.get::<_, String>(0))?;
 
-        conn.execute_batch(include_str!("./schema.sql"))?;
+        migration::migrate(&mut conn)?;
 
-        conn.execute("INSERT INTO info VALUES ('')", [])?;
+        Ok(Self { conn })
+    }
 
+    fn open_reader(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
         Ok(Self { conn })
     }
 
-    pub fn check_existence(&self, username: &str) -> bool {
-        let count: u32 = self
-            .conn
+    #[cfg(test)]
+    pub fn open_in_memory() -> Self {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migration::migrate(&mut conn).unwrap();
+        Self { conn }
+    }
+
+    pub fn check_existence(&self, username: &str) -> rusqlite::Result<bool> {
+        let count: u32 = self.conn.query_row(
+            "SELECT COUNT() FROM user WHERE username IS ?",
+            params![username],
+            |r| r.get(0),
+        )?;
+        Ok(count != 0)
+    }
+
+    pub fn query_credential(&self, username: &str) -> rusqlite::Result<Option<Credential>> {
+        self.conn
             .query_row(
-                "SELECT COUNT() FROM user WHERE username IS ?",
+                "SELECT id, password_hash, password_salt FROM user WHERE username == ?",
                 params![username],
-                |r| r.get(0),
+                |r| {
+                    Ok(Credential {
+                        user_id: r.get(0)?,
+                        password_hash: r.get(1)?,
+                        password_salt: r.get(2)?,
+                    })
+                },
             )
-            .unwrap();
-        count != 0
+            .optional()
     }
 
-    pub fn verify_password(&self, username: &str, password: &str) -> bool {
-        if !self.check_existence(username) {
-            return false;
-        }
-        let (hash, salt): (String, String) = self
-            .conn
+    pub fn query_credential_by_id(&self, user_id: u64) -> rusqlite::Result<Option<Credential>> {
+        self.conn
             .query_row(
-                "SELECT password_hash, password_salt FROM user WHERE username == ?",
-                params![username],
-                |r| Ok((r.get(0)?, r.get(1)?)),
+                "SELECT id, password_hash, password_salt FROM user WHERE id == ?",
+                params![user_id],
+                |r| {
+                    Ok(Credential {
+                        user_id: r.get(0)?,
+                        password_hash: r.get(1)?,
+                        password_salt: r.get(2)?,
+                    })
+                },
             )
-            .unwrap();
-        hash_password(password, salt.as_bytes()) == hash
+            .optional()
     }
 
-    pub fn fetch_info(&self) -> Option<DatabaseInfo> {
-        let result: Result<String, _> =
-            self.conn
-                .query_row(r#"SELECT "json" FROM info"#, [], |r| r.get(0));
-        match result {
-            Ok(s) => serde_json::from_str(&s).ok(),
-            Err(rusqlite::Error::QueryReturnedNoRows) => None,
-            Err(e) => {
-                panic!("{}", e)
-            }
-        }
+    /// Replaces the password hash; the separate salt column is only
+    /// used by legacy hashes and is cleared
+    pub fn update_password_hash(&self, user_id: u64, pw_hash: &str) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE user SET password_hash = ?, password_salt = '' WHERE id = ?",
+            params![pw_hash, user_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn fetch_info(&self) -> rusqlite::Result<Option<DatabaseInfo>> {
+        let json: Option<String> = self
+            .conn
+            .query_row(r#"SELECT "json" FROM info"#, [], |r| r.get(0))
+            .optional()?;
+        Ok(json.and_then(|s| serde_json::from_str(&s).ok()))
     }
 
     // noinspection SqlWithoutWhere
-    pub fn update_info(&self, info: &DatabaseInfo) {
+    pub fn update_info(&self, info: &DatabaseInfo) -> rusqlite::Result<()> {
         let json = serde_json::to_string(info).unwrap();
         self.conn
-            .execute("UPDATE info SET json = ?", params![json])
-            .unwrap();
+            .execute("UPDATE info SET json = ?", params![json])?;
+        Ok(())
     }
 
-    pub fn add_user(&self, username: &str, pw_hash: &str, salt: &str) {
-        self.conn
-            .execute(
-                "INSERT INTO user (username, password_hash, password_salt, signup_time) VALUES (?, ?, ?, ?)",
-                params![username, pw_hash, salt, timestamp()],
-            )
-            .unwrap();
+    /// Returns: id of the new user
+    pub fn add_user(&self, username: &str, pw_hash: &str) -> rusqlite::Result<u64> {
+        self.conn.execute(
+            "INSERT INTO user (username, password_hash, password_salt, signup_time) VALUES (?, ?, '', ?)",
+            params![username, pw_hash, timestamp()],
+        )?;
+        Ok(self.conn.last_insert_rowid() as u64)
     }
 
-    pub fn query_user_id(&self, username: &str) -> Option<u64> {
+    pub fn query_user_id(&self, username: &str) -> rusqlite::Result<Option<u64>> {
         self.conn
             .query_row(
                 "SELECT id FROM user WHERE username IS ?",
                 params![username],
                 |r| r.get(0),
             )
-            .ok()
+            .optional()
     }
 
-    pub fn query_user_profile(&self, id: u64) -> Option<UserProfile> {
-        let user_profile = self.conn.query_row(
-            "SELECT signup_time, name, email, username, gender_code, gender_other FROM user WHERE id IS ?",
-            params![id],
-            |r| {
-                let gender_code: u8 = r.get(4)?;
-                let gender_other: Option<String> = r.get(5)?;
+    pub fn query_user_profile(&self, id: u64) -> rusqlite::Result<Option<UserProfile>> {
+        self.conn
+            .query_row(
+                "SELECT signup_time, name, email, username, gender_code, gender_other FROM user WHERE id IS ?",
+                params![id],
+                |r| {
+                    let gender_code: u8 = r.get(4)?;
+                    let gender_other: Option<String> = r.get(5)?;
 
-                Ok(UserProfile {
-                    signup_time: r.get(0)?,
-                    name: r.get(1)?,
-                    email: r.get(2)?,
-                    username: r.get(3)?,
-                    gender: Gender::from_db_int(gender_code, gender_other)
-                })
-            },
-        );
-        user_profile.ok()
+                    Ok(UserProfile {
+                        signup_time: r.get(0)?,
+                        name: r.get(1)?,
+                        email: r.get(2)?,
+                        username: r.get(3)?,
+                        gender: Gender::from_db_int(gender_code, gender_other),
+                    })
+                },
+            )
+            .optional()
     }
 
-    pub fn update_user_profile(&self, uid: u64, new: &UserProfile) {
+    pub fn update_user_profile(&self, uid: u64, new: &UserProfile) -> rusqlite::Result<()> {
         let gender_int = new.gender.to_db_int();
 
-        self.conn
-            .execute(
-                "UPDATE user
+        self.conn.execute(
+            "UPDATE user
 SET username     = ?,
     name         = ?,
     email        = ?,
     gender_code  = ?,
     gender_other = ?
+WHERE id = ?",
+            params![
+                new.username,
+                new.name,
+                new.email,
+                gender_int.0,
+                gender_int.1,
+                uid,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes the user along with their diary books, entries and sessions;
+    /// the user also leaves books shared with them
+    pub fn delete_user(&self, user_id: u64) -> rusqlite::Result<()> {
+        let transaction = self.conn.unchecked_transaction()?;
+        let book_ids = {
+            let mut statement = transaction.prepare(
+                "SELECT book_id FROM mapping_user_diary_book WHERE user_id IS ? AND role = 0",
+            )?;
+            let rows = statement.query_map(params![user_id], |r| r.get::<_, u64>(0))?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        for book_id in book_ids {
+            Self::delete_diary_book_in(&transaction, book_id)?;
+        }
+        transaction.execute(
+            "DELETE FROM mapping_user_diary_book WHERE user_id IS ?",
+            params![user_id],
+        )?;
+        transaction.execute("DELETE FROM session WHERE user_id IS ?", params![user_id])?;
+        transaction.execute("DELETE FROM user_key WHERE user_id IS ?", params![user_id])?;
+        transaction.execute(
+            "DELETE FROM invite_code WHERE created_by IS ? OR used_by IS ?",
+            params![user_id, user_id],
+        )?;
+        Self::delete_totp_in(&transaction, user_id)?;
+        transaction.execute("DELETE FROM api_token WHERE user_id IS ?", params![user_id])?;
+        transaction.execute("DELETE FROM user WHERE id IS ?", params![user_id])?;
+        transaction.commit()
+    }
+
+    pub fn set_admin(&self, username: &str, admin: bool) -> rusqlite::Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE user SET is_admin = ? WHERE username IS ?",
+            params![admin, username],
+        )?;
+        Ok(updated != 0)
+    }
+
+    /// Unlike [`Database::is_admin`], disabled administrators also count
+    pub fn has_admin_role(&self, user_id: u64) -> rusqlite::Result<bool> {
+        self.conn.query_row(
+            "SELECT COUNT() FROM user WHERE id IS ? AND is_admin",
+            params![user_id],
+            |r| r.get(0),
+        )
+    }
+
+    pub fn is_admin(&self, user_id: u64) -> rusqlite::Result<bool> {
+        self.conn.query_row(
+            "SELECT COUNT() FROM user WHERE id IS ? AND is_admin AND NOT disabled",
+            params![user_id],
+            |r| r.get(0),
+        )
+    }
+
+    pub fn is_user_disabled(&self, user_id: u64) -> rusqlite::Result<bool> {
+        self.conn.query_row(
+            "SELECT COUNT() FROM user WHERE id IS ? AND disabled",
+            params![user_id],
+            |r| r.get(0),
+        )
+    }
+
+    pub fn set_user_disabled(&self, user_id: u64, disabled: bool) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE user SET disabled = ? WHERE id IS ?",
+            params![disabled, user_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_users(&self) -> rusqlite::Result<Vec<UserSummary>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, username, name, email, signup_time, is_admin, disabled
+FROM user
+ORDER BY id",
+        )?;
+        let rows = statement.query_map([], |r| {
+            Ok(UserSummary {
+                id: r.get(0)?,
+                username: r.get(1)?,
+                name: r.get(2)?,
+                email: r.get(3)?,
+                signup_time: r.get(4)?,
+                is_admin: r.get(5)?,
+                disabled: r.get(6)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Sums up data in diary books owned by each user; attachments are
+    /// counted before deduplication
+    pub fn query_storage_usage(&self) -> rusqlite::Result<Vec<StorageUsage>> {
+        let mut statement = self.conn.prepare(
+            "WITH owned AS (SELECT u.user_id, m.diary_id
+               FROM mapping_user_diary_book u
+                        INNER JOIN mapping_diary_book_diary_entry m ON u.book_id = m.book_id
+               WHERE u.role = 0)
+SELECT u.id,
+       u.username,
+       (SELECT COUNT() FROM mapping_user_diary_book b WHERE b.user_id = u.id AND b.role = 0),
+       (SELECT COUNT() FROM owned o WHERE o.user_id = u.id),
+       (SELECT COALESCE(SUM(length(CAST(d.content AS BLOB))), 0)
+        FROM owned o
+                 INNER JOIN diary d ON o.diary_id = d.id
+        WHERE o.user_id = u.id),
+       (SELECT COALESCE(SUM(length(CAST(r.content AS BLOB))), 0)
+        FROM owned o
+                 INNER JOIN diary_revision r ON o.diary_id = r.diary_id
+        WHERE o.user_id = u.id),
+       (SELECT COALESCE(SUM(a.size), 0)
+        FROM owned o
+                 INNER JOIN attachment a ON o.diary_id = a.diary_id
+        WHERE o.user_id = u.id)
+FROM user u
+ORDER BY u.id",
+        )?;
+        let rows = statement.query_map([], |r| {
+            Ok(StorageUsage {
+                user_id: r.get(0)?,
+                username: r.get(1)?,
+                book_count: r.get(2)?,
+                entry_count: r.get(3)?,
+                content_size: r.get(4)?,
+                revision_size: r.get(5)?,
+                attachment_size: r.get(6)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn add_invite_code(
+        &self,
+        code: &str,
+        created_by: u64,
+        expiration_time: Option<u64>,
+    ) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT INTO invite_code (code, created_by, creation_time, expiration_time)
+VALUES (?, ?, ?, ?)",
+            params![code, created_by, timestamp(), expiration_time],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_invite_codes(&self) -> rusqlite::Result<Vec<InviteCode>> {
+        let mut statement = self.conn.prepare(
+            "SELECT i.code, c.username, i.creation_time, i.expiration_time, u.username, i.used_time
+FROM invite_code i
+         LEFT JOIN user c ON i.created_by = c.id
+         LEFT JOIN user u ON i.used_by = u.id
+ORDER BY i.creation_time DESC, i.code",
+        )?;
+        let rows = statement.query_map([], |r| {
+            Ok(InviteCode {
+                code: r.get(0)?,
+                created_by: r.get(1)?,
+                creation_time: r.get(2)?,
+                expiration_time: r.get(3)?,
+                used_by: r.get(4)?,
+                used_time: r.get(5)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Adds the user and redeems the invite code atomically
+    ///
+    /// Returns: false if the code is invalid, in which case the user isn't added
+    pub fn add_invited_user(
+        &self,
+        username: &str,
+        pw_hash: &str,
+        code: &str,
+    ) -> rusqlite::Result<bool> {
+        let transaction = self.conn.unchecked_transaction()?;
+        let user_id = self.add_user(username, pw_hash)?;
+        if !self.redeem_invite_code(code, user_id)? {
+            // rolled back on drop
+            return Ok(false);
+        }
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    pub fn delete_invite_code(&self, code: &str) -> rusqlite::Result<bool> {
+        let deleted = self
+            .conn
+            .execute("DELETE FROM invite_code WHERE code IS ?", params![code])?;
+        Ok(deleted != 0)
+    }
+
+    /// Marks the code as used by `user_id`
+    ///
+    /// Returns: false if the code doesn't exist, is used or has expired
+    pub fn redeem_invite_code(&self, code: &str, user_id: u64) -> rusqlite::Result<bool> {
+        let now = timestamp();
+        let updated = self.conn.execute(
+            "UPDATE invite_code
+SET used_by   = ?,
+    used_time = ?
+WHERE code IS ?
+  AND used_by IS NULL
+  AND (expiration_time IS NULL OR expiration_time > ?)",
+            params![user_id, now, code, now],
+        )?;
+        Ok(updated != 0)
+    }
+
+    /// Returns: id of the new diary book
+    pub fn create_diary_book(
+        &self,
+        name: &str,
+        user_id: u64,
+        encrypted: bool,
+    ) -> rusqlite::Result<u64> {
+        let transaction = self.conn.unchecked_transaction()?;
+        transaction.execute(
+            "INSERT INTO diary_book (name, creation_time, encrypted) VALUES (?, ?, ?)",
+            params![name, timestamp(), encrypted],
+        )?;
+        let book_id = transaction.last_insert_rowid() as u64;
+        transaction.execute(
+            "INSERT INTO mapping_user_diary_book (user_id, book_id) VALUES (?, ?)",
+            params![user_id, book_id],
+        )?;
+        transaction.commit()?;
+        Ok(book_id)
+    }
+
+    pub fn rename_diary_book(&self, book_id: u64, name: &str) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE diary_book SET name = ? WHERE id = ?",
+            params![name, book_id],
+        )?;
+        Ok(())
+    }
+
+    fn diary_book_from_row(r: &Row) -> rusqlite::Result<DiaryBook> {
+        Ok(DiaryBook {
+            id: r.get(0)?,
+            name: r.get(1)?,
+            creation_time: r.get(2)?,
+            encrypted: r.get(3)?,
+            entry_count: r.get(4)?,
+            role: Role::from_db_int(r.get(5)?),
+        })
+    }
+
+    /// Lists all diary books a user has access to along with their entry counts
+    pub fn list_diary_books(&self, user_id: u64) -> rusqlite::Result<Vec<DiaryBook>> {
+        let mut statement = self.conn.prepare(
+            "SELECT b.id, b.name, b.creation_time, b.encrypted, COUNT(d.diary_id), u.role
+FROM diary_book b
+         INNER JOIN mapping_user_diary_book u ON b.id = u.book_id
+         LEFT JOIN mapping_diary_book_diary_entry d ON b.id = d.book_id
+WHERE u.user_id IS ?
+  AND u.accepted
+GROUP BY b.id
+ORDER BY b.id",
+        )?;
+        let rows = statement.query_map(params![user_id], Self::diary_book_from_row)?;
+        rows.collect()
+    }
+
+    /// Fetches a diary book accessible to the user
+    pub fn fetch_diary_book(
+        &self,
+        book_id: u64,
+        user_id: u64,
+    ) -> rusqlite::Result<Option<DiaryBook>> {
+        self.conn
+            .query_row(
+                "SELECT b.id, b.name, b.creation_time, b.encrypted, COUNT(d.diary_id), u.role
+FROM diary_book b
+         INNER JOIN mapping_user_diary_book u ON b.id = u.book_id
+         LEFT JOIN mapping_diary_book_diary_entry d ON b.id = d.book_id
+WHERE b.id IS ?
+  AND u.user_id IS ?
+  AND u.accepted
+GROUP BY b.id",
+                params![book_id, user_id],
+                Self::diary_book_from_row,
+            )
+            .optional()
+    }
+
+    /// Deletes the diary book along with all its entries
+    pub fn delete_diary_book(&self, book_id: u64) -> rusqlite::Result<()> {
+        let transaction = self.conn.unchecked_transaction()?;
+        Self::delete_diary_book_in(&transaction, book_id)?;
+        transaction.commit()
+    }
+
+    fn delete_diary_book_in(conn: &Connection, book_id: u64) -> rusqlite::Result<()> {
+        // mappings reference the entries, so the entry ids are collected first
+        let diary_ids = {
+            let mut statement = conn.prepare(
+                "SELECT diary_id FROM mapping_diary_book_diary_entry WHERE book_id IS ?",
+            )?;
+            let rows = statement.query_map(params![book_id], |r| r.get::<_, u64>(0))?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        conn.execute(
+            "DELETE FROM mapping_diary_book_diary_entry WHERE book_id IS ?",
+            params![book_id],
+        )?;
+        for diary_id in diary_ids {
+            conn.execute("DELETE FROM diary WHERE id IS ?", params![diary_id])?;
+        }
+        conn.execute(
+            "DELETE FROM diary_tombstone WHERE book_id IS ?",
+            params![book_id],
+        )?;
+        conn.execute(
+            "DELETE FROM mapping_user_diary_book WHERE book_id IS ?",
+            params![book_id],
+        )?;
+        conn.execute("DELETE FROM diary_book WHERE id IS ?", params![book_id])?;
+        Ok(())
+    }
+
+    /// Returns the id of the user who owns the diary book
+    pub fn query_book_owner(&self, book_id: u64) -> rusqlite::Result<Option<u64>> {
+        self.conn
+            .query_row(
+                "SELECT user_id FROM mapping_user_diary_book WHERE book_id IS ? AND role = 0",
+                params![book_id],
+                |r| r.get(0),
+            )
+            .optional()
+    }
+
+    /// Returns the role of a user in the diary book; pending invitations
+    /// don't count
+    pub fn query_book_role(&self, book_id: u64, user_id: u64) -> rusqlite::Result<Option<Role>> {
+        self.conn
+            .query_row(
+                "SELECT role FROM mapping_user_diary_book WHERE book_id IS ? AND user_id IS ? AND accepted",
+                params![book_id, user_id],
+                |r| Ok(Role::from_db_int(r.get(0)?)),
+            )
+            .optional()
+    }
+
+    /// Lists members of the diary book, owner first
+    pub fn list_book_members(&self, book_id: u64) -> rusqlite::Result<Vec<Member>> {
+        let mut statement = self.conn.prepare(
+            "SELECT u.id, u.username, m.role, m.accepted
+FROM mapping_user_diary_book m
+         INNER JOIN user u ON m.user_id = u.id
+WHERE m.book_id IS ?
+ORDER BY m.role, u.id",
+        )?;
+        let rows = statement.query_map(params![book_id], |r| {
+            Ok(Member {
+                user_id: r.get(0)?,
+                username: r.get(1)?,
+                role: Role::from_db_int(r.get(2)?),
+                accepted: r.get(3)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Invites the user as a pending member, or changes the role if the user
+    /// is already a member
+    pub fn put_book_member(&self, book_id: u64, user_id: u64, role: Role) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT INTO mapping_user_diary_book (user_id, book_id, role, accepted)
+VALUES (?, ?, ?, 0)
+ON CONFLICT (user_id, book_id) DO UPDATE SET role = excluded.role",
+            params![user_id, book_id, role.to_db_int()],
+        )?;
+        Ok(())
+    }
+
+    /// Returns: false if the user isn't a member
+    pub fn delete_book_member(&self, book_id: u64, user_id: u64) -> rusqlite::Result<bool> {
+        let deleted = self.conn.execute(
+            "DELETE FROM mapping_user_diary_book WHERE book_id IS ? AND user_id IS ? AND role != 0",
+            params![book_id, user_id],
+        )?;
+        Ok(deleted != 0)
+    }
+
+    pub fn list_invitations(&self, user_id: u64) -> rusqlite::Result<Vec<Invitation>> {
+        let mut statement = self.conn.prepare(
+            "SELECT b.id, b.name, u.username, m.role
+FROM mapping_user_diary_book m
+         INNER JOIN diary_book b ON m.book_id = b.id
+         INNER JOIN mapping_user_diary_book o ON m.book_id = o.book_id AND o.role = 0
+         INNER JOIN user u ON o.user_id = u.id
+WHERE m.user_id IS ?
+  AND NOT m.accepted
+ORDER BY b.id",
+        )?;
+        let rows = statement.query_map(params![user_id], |r| {
+            Ok(Invitation {
+                book_id: r.get(0)?,
+                book_name: r.get(1)?,
+                owner: r.get(2)?,
+                role: Role::from_db_int(r.get(3)?),
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Returns: false if there's no pending invitation
+    pub fn accept_invitation(&self, book_id: u64, user_id: u64) -> rusqlite::Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE mapping_user_diary_book SET accepted = 1 WHERE book_id IS ? AND user_id IS ? AND NOT accepted",
+            params![book_id, user_id],
+        )?;
+        Ok(updated != 0)
+    }
+
+    pub fn is_book_encrypted(&self, book_id: u64) -> rusqlite::Result<bool> {
+        self.conn.query_row(
+            "SELECT encrypted FROM diary_book WHERE id IS ?",
+            params![book_id],
+            |r| r.get(0),
+        )
+    }
+
+    /// Returns the id of the diary book the entry belongs to
+    pub fn query_diary_book(&self, diary_id: u64) -> rusqlite::Result<Option<u64>> {
+        self.conn
+            .query_row(
+                "SELECT book_id FROM mapping_diary_book_diary_entry WHERE diary_id IS ?",
+                params![diary_id],
+                |r| r.get(0),
+            )
+            .optional()
+    }
+
+    /// Returns the role of a user in the diary book the entry belongs to
+    pub fn query_diary_role(&self, diary_id: u64, user_id: u64) -> rusqlite::Result<Option<Role>> {
+        self.conn
+            .query_row(
+                "SELECT u.role
+FROM mapping_diary_book_diary_entry d
+         INNER JOIN mapping_user_diary_book u ON d.book_id = u.book_id
+WHERE d.diary_id IS ?
+  AND u.user_id IS ?
+  AND u.accepted",
+                params![diary_id, user_id],
+                |r| Ok(Role::from_db_int(r.get(0)?)),
+            )
+            .optional()
+    }
+
+    /// Reads [`ENTRY_COLUMNS`]
+    fn entry_from_row(r: &Row) -> rusqlite::Result<DiaryEntry> {
+        let tags: String = r.get(9)?;
+        Ok(DiaryEntry {
+            id: r.get(0)?,
+            book_id: r.get(10)?,
+            date: r.get(11)?,
+            version: r.get(12)?,
+            content: r.get(1)?,
+            creation_time: r.get(2)?,
+            encryption: Encryption::from_row(r, 3)?,
+            mood: r.get(6)?,
+            location: r.get(7)?,
+            updated_time: r.get(8)?,
+            tags: serde_json::from_str(&tags)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(9, Type::Text, e.into()))?,
+        })
+    }
+
+    /// Returns the id of the entry of `date` in the diary book
+    pub fn query_diary_id(&self, book_id: u64, date: u32) -> rusqlite::Result<Option<u64>> {
+        self.conn
+            .query_row(
+                "SELECT diary_id FROM mapping_diary_book_diary_entry WHERE book_id IS ? AND date IS ?",
+                params![book_id, date],
+                |r| r.get(0),
+            )
+            .optional()
+    }
+
+    /// Fetches the entry of `date` in the diary book
+    pub fn fetch_diary(&self, book_id: u64, date: u32) -> rusqlite::Result<Option<DiaryEntry>> {
+        self.conn
+            .query_row(
+                &format!(
+                    "SELECT {}
+FROM diary d
+         INNER JOIN mapping_diary_book_diary_entry m ON d.id = m.diary_id
+WHERE m.book_id IS ?
+  AND m.date IS ?",
+                    ENTRY_COLUMNS
+                ),
+                params![book_id, date],
+                Self::entry_from_row,
+            )
+            .optional()
+    }
+
+    pub fn fetch_diary_by_id(&self, diary_id: u64) -> rusqlite::Result<Option<DiaryEntry>> {
+        self.conn
+            .query_row(
+                &format!(
+                    "SELECT {}
+FROM diary d
+         INNER JOIN mapping_diary_book_diary_entry m ON d.id = m.diary_id
+WHERE d.id IS ?",
+                    ENTRY_COLUMNS
+                ),
+                params![diary_id],
+                Self::entry_from_row,
+            )
+            .optional()
+    }
+
+    /// Inserts the entry of `date` into `book_id`, or replaces its content if
+    /// it exists
+    ///
+    /// Returns: id of the entry
+    pub fn update_diary(
+        &self,
+        book_id: u64,
+        date: u32,
+        content: &str,
+        encryption: Option<&Encryption>,
+        metadata: &MetadataUpdate,
+    ) -> rusqlite::Result<u64> {
+        let now = timestamp();
+        let transaction = self.conn.unchecked_transaction()?;
+        let diary_id = self.upsert_diary_in(book_id, date, content, encryption, now, now)?;
+        Self::update_metadata_in(&transaction, diary_id, metadata)?;
+        transaction.commit()?;
+        Ok(diary_id)
+    }
+
+    /// `creation_time` is only used for new entries. Doesn't start its own
+    /// transaction.
+    fn upsert_diary_in(
+        &self,
+        book_id: u64,
+        date: u32,
+        content: &str,
+        encryption: Option<&Encryption>,
+        creation_time: u64,
+        updated_time: u64,
+    ) -> rusqlite::Result<u64> {
+        let [key_id, nonce, algorithm] = Encryption::columns(encryption);
+        let change_seq = Self::next_change_seq(&self.conn)?;
+        if let Some(diary_id) = self.query_diary_id(book_id, date)? {
+            self.conn.execute(
+                "UPDATE diary
+SET content      = ?,
+    updated_time = ?,
+    key_id       = ?,
+    nonce        = ?,
+    algorithm    = ?,
+    version      = version + 1,
+    change_seq   = ?
 WHERE id = ?",
                 params![
-                    new.username,
-                    new.name,
-                    new.email,
-                    gender_int.0,
-                    gender_int.1,
-                    uid,
+                    content,
+                    updated_time,
+                    key_id,
+                    nonce,
+                    algorithm,
+                    change_seq,
+                    diary_id
                 ],
+            )?;
+            return Ok(diary_id);
+        }
+
+        // a recreated entry continues the version of the deleted one
+        let version = self
+            .conn
+            .query_row(
+                "SELECT version + 1 FROM diary_tombstone WHERE book_id IS ? AND date IS ?",
+                params![book_id, date],
+                |r| r.get::<_, u64>(0),
+            )
+            .optional()?
+            .unwrap_or(1);
+        self.conn.execute(
+            "INSERT INTO diary (content, creation_time, updated_time, key_id, nonce, algorithm,
+                   version, change_seq)
+VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                content,
+                creation_time,
+                updated_time,
+                key_id,
+                nonce,
+                algorithm,
+                version,
+                change_seq
+            ],
+        )?;
+        let diary_id = self.conn.last_insert_rowid() as u64;
+        self.conn.execute(
+            "INSERT INTO mapping_diary_book_diary_entry (book_id, diary_id, date) VALUES (?, ?, ?)",
+            params![book_id, diary_id, date],
+        )?;
+        self.conn.execute(
+            "DELETE FROM diary_tombstone WHERE book_id IS ? AND date IS ?",
+            params![book_id, date],
+        )?;
+        Ok(diary_id)
+    }
+
+    /// Doesn't start its own transaction
+    fn update_metadata_in(
+        conn: &Connection,
+        diary_id: u64,
+        metadata: &MetadataUpdate,
+    ) -> rusqlite::Result<()> {
+        if let Some(mood) = metadata.mood {
+            conn.execute(
+                "UPDATE diary SET mood = ? WHERE id = ?",
+                params![mood, diary_id],
+            )?;
+        }
+        if let Some(location) = &metadata.location {
+            conn.execute(
+                "UPDATE diary SET location = ? WHERE id = ?",
+                params![location, diary_id],
+            )?;
+        }
+        if let Some(tags) = &metadata.tags {
+            conn.execute(
+                "DELETE FROM diary_tag WHERE diary_id IS ?",
+                params![diary_id],
+            )?;
+            for tag in tags {
+                conn.execute("INSERT OR IGNORE INTO tag (name) VALUES (?)", params![tag])?;
+                conn.execute(
+                    "INSERT OR IGNORE INTO diary_tag (diary_id, tag_id)
+SELECT ?, id
+FROM tag
+WHERE name IS ?",
+                    params![diary_id, tag],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn delete_diary(&self, diary_id: u64) -> rusqlite::Result<()> {
+        let transaction = self.conn.unchecked_transaction()?;
+        Self::delete_diary_in(&transaction, diary_id)?;
+        transaction.commit()
+    }
+
+    /// Leaves a tombstone of the entry for sync. Doesn't start its own
+    /// transaction.
+    fn delete_diary_in(conn: &Connection, diary_id: u64) -> rusqlite::Result<()> {
+        let change_seq = Self::next_change_seq(conn)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO diary_tombstone (book_id, date, version, change_seq, deletion_time)
+SELECT m.book_id, m.date, d.version + 1, ?, ?
+FROM diary d
+         INNER JOIN mapping_diary_book_diary_entry m ON d.id = m.diary_id
+WHERE d.id IS ?",
+            params![change_seq, timestamp(), diary_id],
+        )?;
+        conn.execute(
+            "DELETE FROM mapping_diary_book_diary_entry WHERE diary_id IS ?",
+            params![diary_id],
+        )?;
+        conn.execute("DELETE FROM diary WHERE id IS ?", params![diary_id])?;
+        Ok(())
+    }
+
+    /// Takes a number from the sync counter. Doesn't start its own
+    /// transaction.
+    fn next_change_seq(conn: &Connection) -> rusqlite::Result<u64> {
+        conn.query_row(
+            "UPDATE sync_counter SET seq = seq + 1 RETURNING seq",
+            [],
+            |r| r.get(0),
+        )
+    }
+
+    /// Returns the number of the last change
+    pub fn query_change_seq(&self) -> rusqlite::Result<u64> {
+        self.conn
+            .query_row("SELECT seq FROM sync_counter", [], |r| r.get(0))
+    }
+
+    fn query_diary_slot(&self, book_id: u64, date: u32) -> rusqlite::Result<SlotState> {
+        let live = self
+            .conn
+            .query_row(
+                "SELECT d.id, d.version
+FROM diary d
+         INNER JOIN mapping_diary_book_diary_entry m ON d.id = m.diary_id
+WHERE m.book_id IS ?
+  AND m.date IS ?",
+                params![book_id, date],
+                |r| {
+                    Ok(SlotState::Live {
+                        id: r.get(0)?,
+                        version: r.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        if let Some(x) = live {
+            return Ok(x);
+        }
+        let deleted = self
+            .conn
+            .query_row(
+                "SELECT version FROM diary_tombstone WHERE book_id IS ? AND date IS ?",
+                params![book_id, date],
+                |r| Ok(SlotState::Deleted { version: r.get(0)? }),
             )
-            .unwrap();
+            .optional()?;
+        Ok(deleted.unwrap_or(SlotState::Missing))
+    }
+
+    /// Applies local changes of a sync in one transaction
+    ///
+    /// Returns: outcomes in the order of `changes`
+    pub fn sync_diaries(
+        &self,
+        book_id: u64,
+        changes: &[SyncChange],
+    ) -> rusqlite::Result<Vec<SyncOutcome>> {
+        let transaction = self.conn.unchecked_transaction()?;
+        let mut outcomes = Vec::with_capacity(changes.len());
+
+        for change in changes {
+            let slot = self.query_diary_slot(book_id, change.date)?;
+            let id = match resolve(change.base_version, change.content.is_none(), slot) {
+                Resolution::Conflict => {
+                    outcomes.push(SyncOutcome::Conflict(SyncConflict {
+                        date: change.date,
+                        base_version: change.base_version,
+                        server_version: slot.version(),
+                        entry: self.fetch_diary(book_id, change.date)?,
+                    }));
+                    continue;
+                }
+                Resolution::Unchanged => None,
+                Resolution::Delete(id) => {
+                    Self::delete_diary_in(&self.conn, id)?;
+                    None
+                }
+                Resolution::Write => {
+                    let now = timestamp();
+                    let id = self.upsert_diary_in(
+                        book_id,
+                        change.date,
+                        change.content.as_deref().unwrap_or_default(),
+                        change.encryption.as_ref(),
+                        now,
+                        now,
+                    )?;
+                    Self::update_metadata_in(&self.conn, id, &change.metadata)?;
+                    Some(id)
+                }
+            };
+            outcomes.push(SyncOutcome::Applied(AppliedChange {
+                date: change.date,
+                id,
+                version: self.query_diary_slot(book_id, change.date)?.version(),
+            }));
+        }
+
+        transaction.commit()?;
+        Ok(outcomes)
+    }
+
+    /// Lists entries of the diary book changed in `(after, until]`
+    pub fn list_changed_diaries(
+        &self,
+        book_id: u64,
+        after: u64,
+        until: u64,
+    ) -> rusqlite::Result<Vec<DiaryEntry>> {
+        let mut statement = self.conn.prepare(&format!(
+            "SELECT {}
+FROM diary d
+         INNER JOIN mapping_diary_book_diary_entry m ON d.id = m.diary_id
+WHERE m.book_id IS ?
+  AND d.change_seq > ?
+  AND d.change_seq <= ?
+ORDER BY m.date",
+            ENTRY_COLUMNS
+        ))?;
+        let rows = statement.query_map(params![book_id, after, until], Self::entry_from_row)?;
+        rows.collect()
+    }
+
+    /// Lists entries of the diary book deleted in `(after, until]`
+    pub fn list_tombstones(
+        &self,
+        book_id: u64,
+        after: u64,
+        until: u64,
+    ) -> rusqlite::Result<Vec<Tombstone>> {
+        let mut statement = self.conn.prepare(
+            "SELECT date, version, deletion_time
+FROM diary_tombstone
+WHERE book_id IS ?
+  AND change_seq > ?
+  AND change_seq <= ?
+ORDER BY date",
+        )?;
+        let rows = statement.query_map(params![book_id, after, until], |r| {
+            Ok(Tombstone {
+                date: r.get(0)?,
+                version: r.get(1)?,
+                deletion_time: r.get(2)?,
+            })
+        })?;
+        rows.collect()
     }
 
-    pub fn create_diary_book(&self, name: &str, user_id: u64) {
-        todo!();
+    /// Builds the FROM and WHERE clauses of entry listing
+    fn entry_filter_clauses(book_id: u64, filter: &EntryFilter) -> (String, Vec<Value>) {
+        let mut sql = String::from(
+            "FROM diary d
+         INNER JOIN mapping_diary_book_diary_entry m ON d.id = m.diary_id
+WHERE m.book_id IS ?",
+        );
+        let mut values = vec![Value::Integer(book_id as i64)];
+        if let Some(mood) = filter.mood {
+            sql.push_str("\n  AND d.mood IS ?");
+            values.push(Value::Integer(mood.into()));
+        }
+        if let Some(tag) = &filter.tag {
+            sql.push_str(
+                "
+  AND EXISTS(SELECT 1
+             FROM diary_tag dt
+                      INNER JOIN tag t ON dt.tag_id = t.id
+             WHERE dt.diary_id = d.id
+               AND t.name IS ?)",
+            );
+            values.push(Value::Text(tag.clone()));
+        }
+        (sql, values)
+    }
+
+    pub fn count_diaries(&self, book_id: u64, filter: &EntryFilter) -> rusqlite::Result<u32> {
+        let (clauses, values) = Self::entry_filter_clauses(book_id, filter);
+        self.conn.query_row(
+            &format!("SELECT COUNT() {}", clauses),
+            params_from_iter(values),
+            |r| r.get(0),
+        )
+    }
+
+    /// Lists diary entries in a book, ordered by date
+    pub fn list_diaries(
+        &self,
+        book_id: u64,
+        filter: &EntryFilter,
+        offset: u32,
+        limit: u32,
+    ) -> rusqlite::Result<Vec<DiaryEntry>> {
+        let (clauses, mut values) = Self::entry_filter_clauses(book_id, filter);
+        values.push(Value::Integer(limit.into()));
+        values.push(Value::Integer(offset.into()));
+        let mut statement = self.conn.prepare(&format!(
+            "SELECT {}
+{}
+ORDER BY m.date
+LIMIT ? OFFSET ?",
+            ENTRY_COLUMNS, clauses
+        ))?;
+        let rows = statement.query_map(params_from_iter(values), Self::entry_from_row)?;
+        rows.collect()
+    }
+
+    /// Counts entries per tag in all diary books a user has access to, or
+    /// only in `book_id` if it's given; most used first
+    pub fn count_tags(
+        &self,
+        user_id: u64,
+        book_id: Option<u64>,
+    ) -> rusqlite::Result<Vec<TagCount>> {
+        let mut statement = self.conn.prepare(
+            "SELECT t.name, COUNT()
+FROM diary_tag dt
+         INNER JOIN tag t ON dt.tag_id = t.id
+         INNER JOIN mapping_diary_book_diary_entry m ON dt.diary_id = m.diary_id
+         INNER JOIN mapping_user_diary_book u ON m.book_id = u.book_id
+WHERE u.user_id IS ?
+  AND u.accepted
+  AND (? IS NULL OR m.book_id IS ?)
+GROUP BY t.id
+ORDER BY COUNT() DESC, t.name",
+        )?;
+        let rows = statement.query_map(params![user_id, book_id, book_id], |r| {
+            Ok(TagCount {
+                tag: r.get(0)?,
+                count: r.get(1)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Visits entries of the user in the order of their dates; contents
+    /// of encrypted entries are `None`
+    pub fn visit_diaries<F>(
+        &self,
+        user_id: u64,
+        book_id: Option<u64>,
+        mut visit: F,
+    ) -> rusqlite::Result<()>
+    where
+        F: FnMut(u32, Option<&str>),
+    {
+        let mut statement = self.conn.prepare(
+            "SELECT m.date, CASE WHEN d.algorithm IS NULL THEN d.content END
+FROM diary d
+         INNER JOIN mapping_diary_book_diary_entry m ON d.id = m.diary_id
+         INNER JOIN mapping_user_diary_book u ON m.book_id = u.book_id
+WHERE u.user_id IS ?
+  AND u.accepted
+  AND (? IS NULL OR m.book_id IS ?)
+ORDER BY m.date",
+        )?;
+        let mut rows = statement.query(params![user_id, book_id, book_id])?;
+        while let Some(r) = rows.next()? {
+            visit(r.get(0)?, r.get_ref(1)?.as_str_or_null()?);
+        }
+        Ok(())
+    }
+
+    /// Lists previous versions of a diary entry, latest first
+    pub fn list_revisions(&self, diary_id: u64) -> rusqlite::Result<Vec<RevisionInfo>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, length(content), revision_time
+FROM diary_revision
+WHERE diary_id IS ?
+ORDER BY id DESC",
+        )?;
+        let rows = statement.query_map(params![diary_id], |r| {
+            Ok(RevisionInfo {
+                id: r.get(0)?,
+                diary_id,
+                length: r.get(1)?,
+                revision_time: r.get(2)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn fetch_revision(
+        &self,
+        diary_id: u64,
+        revision_id: u64,
+    ) -> rusqlite::Result<Option<Revision>> {
         self.conn
-            .execute(
-                "INSERT INTO diary_book (name, creation_time) VALUES (?, ?, ?)",
-                params![name, timestamp()],
+            .query_row(
+                "SELECT content, revision_time, key_id, nonce, algorithm
+FROM diary_revision
+WHERE id IS ?
+  AND diary_id IS ?",
+                params![revision_id, diary_id],
+                |r| {
+                    Ok(Revision {
+                        id: revision_id,
+                        diary_id,
+                        content: r.get(0)?,
+                        revision_time: r.get(1)?,
+                        encryption: Encryption::from_row(r, 2)?,
+                    })
+                },
             )
-            .unwrap();
+            .optional()
+    }
+
+    /// Sets the content of the entry to that of a revision; the replaced
+    /// content is recorded as a new revision by the trigger
+    ///
+    /// Returns: false if the revision doesn't exist
+    pub fn restore_revision(&self, diary_id: u64, revision_id: u64) -> rusqlite::Result<bool> {
+        let transaction = self.conn.unchecked_transaction()?;
+        let change_seq = Self::next_change_seq(&transaction)?;
+        let updated = transaction.execute(
+            "UPDATE diary
+SET (content, key_id, nonce, algorithm) = (SELECT content, key_id, nonce, algorithm
+                                           FROM diary_revision
+                                           WHERE id IS ?
+                                             AND diary_id IS ?),
+    updated_time                        = ?,
+    version                             = version + 1,
+    change_seq                          = ?
+WHERE id IS ?
+  AND EXISTS(SELECT 1 FROM diary_revision WHERE id IS ? AND diary_id IS ?)",
+            params![
+                revision_id,
+                diary_id,
+                timestamp(),
+                change_seq,
+                diary_id,
+                revision_id,
+                diary_id
+            ],
+        )?;
+        transaction.commit()?;
+        Ok(updated != 0)
+    }
+
+    pub fn add_attachment(
+        &self,
+        diary_id: u64,
+        hash: &str,
+        file_name: &str,
+        mime_type: &str,
+        size: u64,
+    ) -> rusqlite::Result<Attachment> {
+        let creation_time = timestamp();
+        self.conn.execute(
+            "INSERT INTO attachment (diary_id, hash, file_name, mime_type, size, creation_time)
+VALUES (?, ?, ?, ?, ?, ?)",
+            params![diary_id, hash, file_name, mime_type, size, creation_time],
+        )?;
+        Ok(Attachment {
+            id: self.conn.last_insert_rowid() as u64,
+            diary_id,
+            file_name: file_name.into(),
+            mime_type: mime_type.into(),
+            size,
+            hash: hash.into(),
+            creation_time,
+        })
+    }
+
+    fn attachment_from_row(r: &Row) -> rusqlite::Result<Attachment> {
+        Ok(Attachment {
+            id: r.get(0)?,
+            diary_id: r.get(1)?,
+            file_name: r.get(2)?,
+            mime_type: r.get(3)?,
+            size: r.get(4)?,
+            hash: r.get(5)?,
+            creation_time: r.get(6)?,
+        })
+    }
+
+    pub fn list_attachments(&self, diary_id: u64) -> rusqlite::Result<Vec<Attachment>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, diary_id, file_name, mime_type, size, hash, creation_time
+FROM attachment
+WHERE diary_id IS ?
+ORDER BY id",
+        )?;
+        let rows = statement.query_map(params![diary_id], Self::attachment_from_row)?;
+        rows.collect()
+    }
+
+    pub fn query_attachment(&self, id: u64) -> rusqlite::Result<Option<Attachment>> {
         self.conn
-            .execute(
-                "INSERT INTO user_diary_book (user_id, diary_book_id) VALUES (?, ?)",
-                params![user_id, 0],
+            .query_row(
+                "SELECT id, diary_id, file_name, mime_type, size, hash, creation_time
+FROM attachment
+WHERE id IS ?",
+                params![id],
+                Self::attachment_from_row,
             )
-            .unwrap();
+            .optional()
+    }
+
+    pub fn delete_attachment(&self, id: u64) -> rusqlite::Result<()> {
+        self.conn
+            .execute("DELETE FROM attachment WHERE id IS ?", params![id])?;
+        Ok(())
+    }
+
+    /// Returns and forgets hashes of files no longer referenced; the ones
+    /// referenced again by new uploads are skipped
+    pub fn take_orphan_hashes(&self) -> rusqlite::Result<Vec<String>> {
+        let transaction = self.conn.unchecked_transaction()?;
+        let hashes = {
+            let mut statement = transaction.prepare(
+                "SELECT hash
+FROM attachment_orphan
+WHERE hash NOT IN (SELECT hash FROM attachment)",
+            )?;
+            let rows = statement.query_map([], |r| r.get::<_, String>(0))?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        transaction.execute("DELETE FROM attachment_orphan", [])?;
+        transaction.commit()?;
+        Ok(hashes)
+    }
+
+    /// Collects diary books of a user with all their entries; only `book_id`
+    /// is included if it's given
+    pub fn export_diary_books(
+        &self,
+        user_id: u64,
+        book_id: Option<u64>,
+    ) -> rusqlite::Result<Vec<BookExport>> {
+        let mut books = Vec::new();
+        for book in self.list_diary_books(user_id)? {
+            if book_id.is_some_and(|x| x != book.id) {
+                continue;
+            }
+            let entries = self
+                .list_diaries(book.id, &EntryFilter::default(), 0, u32::MAX)?
+                .into_iter()
+                .map(|x| EntryExport {
+                    date: x.date,
+                    content: x.content,
+                    creation_time: x.creation_time,
+                    updated_time: Some(x.updated_time),
+                    encryption: x.encryption,
+                    tags: x.tags,
+                    mood: x.mood,
+                    location: x.location,
+                })
+                .collect();
+            books.push(BookExport {
+                name: book.name,
+                creation_time: book.creation_time,
+                encrypted: book.encrypted,
+                entries,
+            });
+        }
+        Ok(books)
+    }
+
+    /// Imports diary books in one transaction
+    ///
+    /// Entries go to `target_book` if it's given, otherwise to the user's
+    /// diary book with the same name, which is created if missing.
+    pub fn import_diaries(
+        &self,
+        user_id: u64,
+        books: &[BookExport],
+        target_book: Option<u64>,
+        overwrite: bool,
+    ) -> rusqlite::Result<ImportReport> {
+        let transaction = self.conn.unchecked_transaction()?;
+        let mut report = ImportReport::default();
+
+        for book in books {
+            let book_id = match target_book {
+                Some(x) => x,
+                None => self.find_or_create_diary_book(user_id, book)?,
+            };
+
+            for entry in &book.entries {
+                let metadata = MetadataUpdate {
+                    tags: Some(entry.tags.clone()),
+                    mood: Some(entry.mood),
+                    location: Some(entry.location.clone()),
+                };
+                let existing = self.query_diary_id(book_id, entry.date)?;
+                if existing.is_some() && !overwrite {
+                    report.unchanged += 1;
+                    continue;
+                }
+                let diary_id = self.upsert_diary_in(
+                    book_id,
+                    entry.date,
+                    &entry.content,
+                    entry.encryption.as_ref(),
+                    entry.creation_time,
+                    match existing {
+                        Some(_) => timestamp(),
+                        None => entry.updated_time.unwrap_or(entry.creation_time),
+                    },
+                )?;
+                Self::update_metadata_in(&self.conn, diary_id, &metadata)?;
+                match existing {
+                    Some(_) => report.updated += 1,
+                    None => report.created += 1,
+                }
+            }
+        }
+
+        transaction.commit()?;
+        Ok(report)
+    }
+
+    /// Doesn't start its own transaction
+    fn find_or_create_diary_book(&self, user_id: u64, book: &BookExport) -> rusqlite::Result<u64> {
+        let existing = self
+            .conn
+            .query_row(
+                "SELECT b.id
+FROM diary_book b
+         INNER JOIN mapping_user_diary_book u ON b.id = u.book_id
+WHERE u.user_id IS ?
+  AND u.role = 0
+  AND b.name IS ?
+  AND b.encrypted IS ?
+ORDER BY b.id
+LIMIT 1",
+                params![user_id, book.name, book.encrypted],
+                |r| r.get(0),
+            )
+            .optional()?;
+        if let Some(id) = existing {
+            return Ok(id);
+        }
+
+        self.conn.execute(
+            "INSERT INTO diary_book (name, creation_time, encrypted) VALUES (?, ?, ?)",
+            params![book.name, book.creation_time, book.encrypted],
+        )?;
+        let book_id = self.conn.last_insert_rowid() as u64;
+        self.conn.execute(
+            "INSERT INTO mapping_user_diary_book (user_id, book_id) VALUES (?, ?)",
+            params![user_id, book_id],
+        )?;
+        Ok(book_id)
+    }
+
+    /// Builds the FROM and WHERE clauses of search queries
+    fn search_clauses(user_id: u64, query: &SearchFilter) -> (String, Vec<Value>) {
+        let mut sql = String::from(
+            "FROM diary d
+         INNER JOIN mapping_diary_book_diary_entry m ON d.id = m.diary_id
+         INNER JOIN mapping_user_diary_book u ON m.book_id = u.book_id",
+        );
+        if query.match_query.is_some() {
+            sql.push_str("\n         INNER JOIN diary_fts ON d.id = diary_fts.rowid");
+        }
+        sql.push_str(
+            "
+WHERE u.user_id IS ?
+  AND u.accepted
+  AND (? IS NULL OR m.book_id IS ?)
+  AND m.date BETWEEN ? AND ?
+  AND d.algorithm IS NULL",
+        );
+        let book_id = query.book_id.map(|x| Value::Integer(x as i64));
+        let mut values = vec![
+            Value::Integer(user_id as i64),
+            book_id.clone().unwrap_or(Value::Null),
+            book_id.unwrap_or(Value::Null),
+            Value::Integer(query.from.into()),
+            Value::Integer(query.to.into()),
+        ];
+        if let Some(match_query) = &query.match_query {
+            sql.push_str("\n  AND diary_fts MATCH ?");
+            values.push(Value::Text(match_query.clone()));
+        }
+        for pattern in &query.like_patterns {
+            sql.push_str("\n  AND d.content LIKE ? ESCAPE '\\'");
+            values.push(Value::Text(pattern.clone()));
+        }
+        (sql, values)
+    }
+
+    pub fn count_search_results(
+        &self,
+        user_id: u64,
+        query: &SearchFilter,
+    ) -> rusqlite::Result<u32> {
+        let (clauses, values) = Self::search_clauses(user_id, query);
+        self.conn.query_row(
+            &format!("SELECT COUNT() {}", clauses),
+            params_from_iter(values),
+            |r| r.get(0),
+        )
+    }
+
+    /// Searches entries in all diary books of a user
+    ///
+    /// Results are ordered by relevance if the query can use the full-text
+    /// index, otherwise by date, latest first.
+    pub fn search_diaries(
+        &self,
+        user_id: u64,
+        query: &SearchFilter,
+        offset: u32,
+        limit: u32,
+    ) -> rusqlite::Result<Vec<SearchHit>> {
+        let (clauses, mut values) = Self::search_clauses(user_id, query);
+        let sql = match query.match_query {
+            Some(_) => {
+                values.splice(
+                    0..0,
+                    [
+                        Value::Text(SNIPPET_MARK_START.to_string()),
+                        Value::Text(SNIPPET_MARK_END.to_string()),
+                    ],
+                );
+                format!(
+                    "SELECT d.id, m.book_id, m.date, snippet(diary_fts, 0, ?, ?, '...', {}), diary_fts.rank
+{}
+ORDER BY diary_fts.rank
+LIMIT ? OFFSET ?",
+                    SNIPPET_LENGTH, clauses
+                )
+            }
+            None => format!(
+                "SELECT d.id, m.book_id, m.date, substr(d.content, 1, {}), NULL
+{}
+ORDER BY m.date DESC
+LIMIT ? OFFSET ?",
+                SNIPPET_LENGTH, clauses
+            ),
+        };
+        values.push(Value::Integer(limit.into()));
+        values.push(Value::Integer(offset.into()));
+
+        let mut statement = self.conn.prepare(&sql)?;
+        let rows = statement.query_map(params_from_iter(values), |r| {
+            Ok(SearchHit {
+                id: r.get(0)?,
+                book_id: r.get(1)?,
+                date: r.get(2)?,
+                snippet: highlight_snippet(
+                    &r.get::<_, String>(3)?,
+                    &query.mark_start,
+                    &query.mark_end,
+                ),
+                rank: r.get(4)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn query_totp(&self, user_id: u64) -> rusqlite::Result<Option<TotpSecret>> {
+        self.conn
+            .query_row(
+                "SELECT secret, enabled, last_step FROM user_totp WHERE user_id IS ?",
+                params![user_id],
+                |r| {
+                    Ok(TotpSecret {
+                        secret: r.get(0)?,
+                        enabled: r.get(1)?,
+                        last_step: r.get(2)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    pub fn is_totp_enabled(&self, user_id: u64) -> rusqlite::Result<bool> {
+        Ok(self.query_totp(user_id)?.is_some_and(|x| x.enabled))
+    }
+
+    /// Sets an unconfirmed secret
+    pub fn put_totp_secret(&self, user_id: u64, secret: &str) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO user_totp (user_id, secret, creation_time) VALUES (?, ?, ?)",
+            params![user_id, secret, timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// Confirms the secret, and replaces the recovery codes
+    pub fn enable_totp(
+        &self,
+        user_id: u64,
+        step: u64,
+        recovery_code_hashes: &[String],
+    ) -> rusqlite::Result<()> {
+        let transaction = self.conn.unchecked_transaction()?;
+        transaction.execute(
+            "UPDATE user_totp SET enabled = 1, last_step = ? WHERE user_id IS ?",
+            params![step, user_id],
+        )?;
+        transaction.execute(
+            "DELETE FROM recovery_code WHERE user_id IS ?",
+            params![user_id],
+        )?;
+        for hash in recovery_code_hashes {
+            transaction.execute(
+                "INSERT INTO recovery_code (user_id, hash) VALUES (?, ?)",
+                params![user_id, hash],
+            )?;
+        }
+        transaction.commit()
+    }
+
+    /// Records the time step of an accepted code
+    ///
+    /// Returns: false if a code of the same or a later step was accepted
+    pub fn update_totp_step(&self, user_id: u64, step: u64) -> rusqlite::Result<bool> {
+        let updated = self.conn.execute(
+            "UPDATE user_totp SET last_step = ? WHERE user_id IS ? AND last_step < ?",
+            params![step, user_id, step],
+        )?;
+        Ok(updated != 0)
+    }
+
+    /// Consumes a recovery code
+    ///
+    /// Returns: false if the code doesn't exist
+    pub fn use_recovery_code(&self, user_id: u64, hash: &str) -> rusqlite::Result<bool> {
+        let deleted = self.conn.execute(
+            "DELETE FROM recovery_code WHERE user_id IS ? AND hash IS ?",
+            params![user_id, hash],
+        )?;
+        Ok(deleted != 0)
+    }
+
+    pub fn count_recovery_codes(&self, user_id: u64) -> rusqlite::Result<u32> {
+        self.conn.query_row(
+            "SELECT COUNT(*) FROM recovery_code WHERE user_id IS ?",
+            params![user_id],
+            |r| r.get(0),
+        )
+    }
+
+    /// Disables two-factor authentication of the user
+    pub fn delete_totp(&self, user_id: u64) -> rusqlite::Result<()> {
+        let transaction = self.conn.unchecked_transaction()?;
+        Self::delete_totp_in(&transaction, user_id)?;
+        transaction.commit()
+    }
+
+    fn delete_totp_in(conn: &Connection, user_id: u64) -> rusqlite::Result<()> {
+        conn.execute(
+            "DELETE FROM recovery_code WHERE user_id IS ?",
+            params![user_id],
+        )?;
+        conn.execute("DELETE FROM user_totp WHERE user_id IS ?", params![user_id])?;
+        Ok(())
+    }
+
+    pub fn add_api_token(
+        &self,
+        user_id: u64,
+        name: &str,
+        token_hash: &str,
+        scope: TokenScope,
+        expiration_time: Option<u64>,
+    ) -> rusqlite::Result<u64> {
+        self.conn.execute(
+            "INSERT INTO api_token (user_id, name, token_hash, scope, creation_time, expiration_time)
+VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                user_id,
+                name,
+                token_hash,
+                scope.to_db_int(),
+                timestamp(),
+                expiration_time
+            ],
+        )?;
+        Ok(self.conn.last_insert_rowid() as u64)
+    }
+
+    fn api_token_from_row(r: &Row) -> rusqlite::Result<ApiToken> {
+        Ok(ApiToken {
+            id: r.get(0)?,
+            name: r.get(1)?,
+            scope: TokenScope::from_db_int(r.get(2)?),
+            creation_time: r.get(3)?,
+            expiration_time: r.get(4)?,
+            last_used_time: r.get(5)?,
+        })
+    }
+
+    pub fn list_api_tokens(&self, user_id: u64) -> rusqlite::Result<Vec<ApiToken>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, name, scope, creation_time, expiration_time, last_used_time
+FROM api_token
+WHERE user_id IS ?
+ORDER BY id",
+        )?;
+        let rows = statement.query_map(params![user_id], Self::api_token_from_row)?;
+        rows.collect()
+    }
+
+    /// Looks up an unexpired token of an enabled user
+    pub fn query_api_token(
+        &self,
+        token_hash: &str,
+        now: u64,
+    ) -> rusqlite::Result<Option<TokenOwner>> {
+        self.conn
+            .query_row(
+                "SELECT t.id, t.name, t.scope, t.creation_time, t.expiration_time, t.last_used_time,
+       u.id, u.username
+FROM api_token t
+         INNER JOIN user u ON t.user_id = u.id
+WHERE t.token_hash IS ?
+  AND (t.expiration_time IS NULL OR t.expiration_time > ?)
+  AND NOT u.disabled",
+                params![token_hash, now],
+                |r| {
+                    Ok(TokenOwner {
+                        token: Self::api_token_from_row(r)?,
+                        user_id: r.get(6)?,
+                        username: r.get(7)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    pub fn update_api_token_last_used(&self, id: u64, time: u64) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE api_token SET last_used_time = ? WHERE id IS ?",
+            params![time, id],
+        )?;
+        Ok(())
+    }
+
+    /// Returns: false if the user has no token of the id
+    pub fn delete_api_token(&self, id: u64, user_id: u64) -> rusqlite::Result<bool> {
+        let deleted = self.conn.execute(
+            "DELETE FROM api_token WHERE id IS ? AND user_id IS ?",
+            params![id, user_id],
+        )?;
+        Ok(deleted != 0)
+    }
+
+    pub fn delete_user_api_tokens(&self, user_id: u64) -> rusqlite::Result<()> {
+        self.conn
+            .execute("DELETE FROM api_token WHERE user_id IS ?", params![user_id])?;
+        Ok(())
+    }
+
+    pub fn list_user_keys(&self, user_id: u64) -> rusqlite::Result<Vec<UserKey>> {
+        let mut statement = self.conn.prepare(
+            "SELECT key_id, wrapped_key, params, update_time
+FROM user_key
+WHERE user_id IS ?
+ORDER BY update_time",
+        )?;
+        let rows = statement.query_map(params![user_id], |r| {
+            Ok(UserKey {
+                key_id: r.get(0)?,
+                wrapped_key: r.get(1)?,
+                params: r.get(2)?,
+                update_time: r.get(3)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn check_user_key(&self, user_id: u64, key_id: &str) -> rusqlite::Result<bool> {
+        let count: u32 = self.conn.query_row(
+            "SELECT COUNT() FROM user_key WHERE user_id IS ? AND key_id IS ?",
+            params![user_id, key_id],
+            |r| r.get(0),
+        )?;
+        Ok(count != 0)
+    }
+
+    /// Inserts the wrapped key, or replaces it if `key_id` exists
+    pub fn put_user_key(
+        &self,
+        user_id: u64,
+        key_id: &str,
+        wrapped_key: &str,
+        key_params: &str,
+    ) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT INTO user_key (user_id, key_id, wrapped_key, params, update_time)
+VALUES (?, ?, ?, ?, ?)
+ON CONFLICT (user_id, key_id) DO UPDATE SET wrapped_key = excluded.wrapped_key,
+                                            params      = excluded.params,
+                                            update_time = excluded.update_time",
+            params![user_id, key_id, wrapped_key, key_params, timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// Returns: id of the new session
+    pub fn create_session(
+        &self,
+        user_id: u64,
+        refresh_token_hash: &str,
+        expiration_time: u64,
+    ) -> rusqlite::Result<u64> {
+        let now = timestamp();
+        self.conn.execute(
+            "DELETE FROM session WHERE expiration_time < ?",
+            params![now],
+        )?;
+        self.conn.execute(
+            "INSERT INTO session (user_id, refresh_token_hash, creation_time, expiration_time)
+VALUES (?, ?, ?, ?)",
+            params![user_id, refresh_token_hash, now, expiration_time],
+        )?;
+        Ok(self.conn.last_insert_rowid() as u64)
+    }
+
+    pub fn query_session_by_refresh_token(
+        &self,
+        refresh_token_hash: &str,
+    ) -> rusqlite::Result<Option<Session>> {
+        self.conn
+            .query_row(
+                "SELECT s.id, s.user_id, u.username, s.expiration_time
+FROM session s
+         INNER JOIN user u ON s.user_id = u.id
+WHERE s.refresh_token_hash IS ?
+  AND NOT u.disabled",
+                params![refresh_token_hash],
+                |r| {
+                    Ok(Session {
+                        id: r.get(0)?,
+                        user_id: r.get(1)?,
+                        username: r.get(2)?,
+                        expiration_time: r.get(3)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    /// Sessions of disabled users are invalid
+    pub fn check_session(&self, session_id: u64, user_id: u64) -> rusqlite::Result<bool> {
+        let count: u32 = self.conn.query_row(
+            "SELECT COUNT()
+FROM session s
+         INNER JOIN user u ON s.user_id = u.id
+WHERE s.id IS ?
+  AND s.user_id IS ?
+  AND NOT u.disabled",
+            params![session_id, user_id],
+            |r| r.get(0),
+        )?;
+        Ok(count != 0)
+    }
+
+    /// Replaces the refresh token of a session
+    pub fn rotate_refresh_token(
+        &self,
+        session_id: u64,
+        refresh_token_hash: &str,
+        expiration_time: u64,
+    ) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE session SET refresh_token_hash = ?, expiration_time = ? WHERE id = ?",
+            params![refresh_token_hash, expiration_time, session_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_session(&self, session_id: u64) -> rusqlite::Result<()> {
+        self.conn
+            .execute("DELETE FROM session WHERE id IS ?", params![session_id])?;
+        Ok(())
+    }
+
+    /// Revokes all sessions of a user except `session_id`
+    pub fn delete_other_sessions(&self, user_id: u64, session_id: u64) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "DELETE FROM session WHERE user_id IS ? AND id IS NOT ?",
+            params![user_id, session_id],
+        )?;
+        Ok(())
+    }
+
+    /// Revokes all sessions of a user
+    pub fn delete_user_sessions(&self, user_id: u64) -> rusqlite::Result<()> {
+        self.conn
+            .execute("DELETE FROM session WHERE user_id IS ?", params![user_id])?;
+        Ok(())
     }
 }
@@ -1,32 +1,178 @@
+use axum::extract::Query;
+use axum::http::HeaderMap;
 use axum::response::IntoResponse;
 use axum_extra::extract::CookieJar;
 use serde::{Deserialize, Serialize};
 
-use crate::{get_session, lock_database, ResponseJson};
+use crate::routes::diary::database;
+use crate::routes::diary::database::EntryFilter;
+use crate::routes::diary::etag::{check_if_match, entity_tag, etag_header};
+use crate::routes::diary::sharing::Role;
+use crate::routes::diary::{attachment, failure_response, ResponseStatus};
+use crate::{get_session, ResponseJson};
+
+/// in characters
+const MAX_NAME_LENGTH: usize = 100;
 
 #[derive(Serialize, Deserialize)]
 pub struct Form {
     name: String,
+    /// entries of encrypted books are encrypted by clients; this can't be
+    /// changed later
+    encrypted: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RenameForm {
+    id: u64,
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct FetchQuery {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteQuery {
+    id: u64,
+    /// if true, also delete all entries in the book; otherwise
+    /// the deletion is refused when the book is not empty
+    cascade: Option<bool>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiaryBook {
+    pub id: u64,
+    pub name: String,
+    pub creation_time: u64,
+    pub encrypted: bool,
+    pub entry_count: u32,
+    /// role of the current user
+    pub role: Role,
+}
+
+impl DiaryBook {
+    /// Covers the book itself, but not its entries or the user's role
+    pub(crate) fn entity_tag(&self) -> String {
+        entity_tag(&(self.id, &self.name, self.creation_time, self.encrypted))
+    }
+}
+
+/// Trims the name
+///
+/// Returns: `None` if it's empty or too long
+pub(crate) fn normalize_book_name(name: &str) -> Option<String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return None;
+    }
+    Some(name.to_string())
 }
 
 // with JWT cookie
 pub async fn create(cookies: CookieJar, axum::Form(form): axum::Form<Form>) -> impl IntoResponse {
     let claims = get_session!(&cookies);
 
-    let database = lock_database!();
-    database.create_diary_book(&form.name, claims.user_id);
+    let Some(name) = normalize_book_name(&form.name) else {
+        return failure_response(ResponseStatus::InvalidBookName).into_response();
+    };
+    database::write(move |db| {
+        let encrypted = form.encrypted.unwrap_or(false);
+        let book_id = db.create_diary_book(&name, claims.user_id, encrypted)?;
+        Ok(ResponseJson::ok(book_id).into_response())
+    })
+    .await
+    .into_response()
+}
+
+pub async fn fetch(cookies: CookieJar, Query(query): Query<FetchQuery>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        Ok(match db.fetch_diary_book(query.id, claims.user_id)? {
+            None => failure_response(ResponseStatus::NoRecord).into_response(),
+            Some(x) => (etag_header(x.entity_tag()), ResponseJson::ok(x)).into_response(),
+        })
+    })
+    .await
+    .into_response()
+}
+
+pub async fn update(
+    cookies: CookieJar,
+    headers: HeaderMap,
+    axum::Form(form): axum::Form<RenameForm>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    let Some(name) = normalize_book_name(&form.name) else {
+        return failure_response(ResponseStatus::InvalidBookName).into_response();
+    };
+    database::write(move |db| {
+        if db.query_book_owner(form.id)? != Some(claims.user_id) {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        }
+        let current = db.fetch_diary_book(form.id, claims.user_id)?;
+        if let Some(x) = check_if_match(&headers, current.map(|x| x.entity_tag()).as_deref()) {
+            return Ok(x);
+        }
 
-    ResponseJson::ok(()).into()
+        db.rename_diary_book(form.id, &name)?;
+        let tag = db
+            .fetch_diary_book(form.id, claims.user_id)?
+            .map(|x| x.entity_tag());
+        Ok((tag.map(etag_header), ResponseJson::ok(())).into_response())
+    })
+    .await
+    .into_response()
 }
 
-pub async fn update() -> impl IntoResponse {
-    todo!()
+pub async fn list(cookies: CookieJar) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        let books = db.list_diary_books(claims.user_id)?;
+        Ok(ResponseJson::ok(books).into_response())
+    })
+    .await
+    .into_response()
 }
 
-pub async fn list() -> impl IntoResponse {
-    todo!()
+pub async fn delete(
+    cookies: CookieJar,
+    headers: HeaderMap,
+    Query(query): Query<DeleteQuery>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::write(move |db| {
+        if db.query_book_owner(query.id)? != Some(claims.user_id) {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        }
+        let current = db.fetch_diary_book(query.id, claims.user_id)?;
+        if let Some(x) = check_if_match(&headers, current.map(|x| x.entity_tag()).as_deref()) {
+            return Ok(x);
+        }
+        if !query.cascade.unwrap_or(false)
+            && db.count_diaries(query.id, &EntryFilter::default())? != 0
+        {
+            return Ok(failure_response(ResponseStatus::BookNotEmpty).into_response());
+        }
+
+        db.delete_diary_book(query.id)?;
+        attachment::remove_orphan_files(db)?;
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
 }
 
-pub async fn delete() -> impl IntoResponse {
-    todo!()
+#[test]
+fn book_names() {
+    assert_eq!(normalize_book_name("  旅行 ").as_deref(), Some("旅行"));
+    assert_eq!(normalize_book_name(" \t"), None);
+    assert!(normalize_book_name(&"a".repeat(MAX_NAME_LENGTH)).is_some());
+    assert_eq!(normalize_book_name(&"a".repeat(MAX_NAME_LENGTH + 1)), None);
 }
@@ -1,15 +1,491 @@
-use crate::routes::diary::FetchQuery;
-use axum::extract::Query;
+use std::ops::RangeInclusive;
+
+use axum::extract::{Path, Query};
+use axum::http::HeaderMap;
 use axum::response::IntoResponse;
+use axum::Json;
+use axum_extra::extract::CookieJar;
+use serde::{Deserialize, Serialize};
+
+use crate::routes::diary::database;
+use crate::routes::diary::database::{Database, EntryFilter, MetadataUpdate, SearchFilter};
+use crate::routes::diary::encryption::Encryption;
+use crate::routes::diary::etag::{check_if_match, entity_tag, etag_header};
+use crate::routes::diary::sharing::{check_role, Role};
+use crate::routes::diary::tag::normalize_tags;
+use crate::routes::diary::{
+    attachment, failure_response, is_valid_date, nullable, FetchQuery, ResponseStatus,
+};
+use crate::{get_session, ResponseJson};
+
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+/// Shorter terms can't be matched by the trigram tokenizer
+const MIN_SEARCH_TERM_LENGTH: usize = 3;
+const MOOD_RANGE: RangeInclusive<u8> = 1..=5;
+/// in characters
+const MAX_LOCATION_LENGTH: usize = 256;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiaryEntry {
+    pub id: u64,
+    pub book_id: u64,
+    /// date integer, e.g. 20230415; unique within the diary book
+    pub date: u32,
+    /// ciphertext if `encryption` is present
+    pub content: String,
+    pub creation_time: u64,
+    pub updated_time: u64,
+    pub encryption: Option<Encryption>,
+    pub tags: Vec<String>,
+    /// 1 to 5
+    pub mood: Option<u8>,
+    pub location: Option<String>,
+    /// incremented on each change of the entry, see [`crate::routes::diary::sync`]
+    pub version: u64,
+}
+
+/// Absent metadata fields are left unchanged, and `null` clears them
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateForm {
+    pub book_id: u64,
+    /// date integer, e.g. 20230415
+    pub date: u32,
+    pub content: String,
+    /// required for encrypted diary books, and must be absent otherwise
+    pub encryption: Option<Encryption>,
+    pub tags: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub mood: Option<Option<u8>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub location: Option<Option<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    pub book_id: u64,
+    /// only lists entries with this tag
+    pub tag: Option<String>,
+    /// only lists entries with this mood
+    pub mood: Option<u8>,
+    pub offset: Option<u32>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiaryList {
+    pub total: u32,
+    pub entries: Vec<DiaryEntry>,
+}
+
+/// Matched text in snippets is surrounded by `mark_start` and `mark_end`,
+/// which default to `<mark>` and `</mark>`; terms shorter than three
+/// characters aren't highlighted. The snippet text is HTML-escaped, and so
+/// are the markers unless they're tags in [`MARKER_TAGS`].
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub book_id: Option<u64>,
+    /// inclusive start date, e.g. 20230101
+    pub from: Option<u32>,
+    /// inclusive end date
+    pub to: Option<u32>,
+    pub mark_start: Option<String>,
+    pub mark_end: Option<String>,
+    pub offset: Option<u32>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchHit {
+    pub id: u64,
+    pub book_id: u64,
+    pub date: u32,
+    pub snippet: String,
+    /// BM25 score, smaller is better; absent if the full-text index isn't used
+    pub rank: Option<f64>,
+}
 
-pub async fn fetch(Query(query): Query<FetchQuery>) -> impl IntoResponse {
-    todo!()
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResult {
+    pub total: u32,
+    pub hits: Vec<SearchHit>,
 }
 
-pub async fn delete() -> impl IntoResponse {
-    todo!()
+pub(crate) fn is_valid_mood(mood: u8) -> bool {
+    MOOD_RANGE.contains(&mood)
 }
 
-pub async fn list() -> impl IntoResponse {
-    todo!()
+pub(crate) fn is_valid_location(location: &str) -> bool {
+    location.chars().count() <= MAX_LOCATION_LENGTH
+}
+
+/// Validates metadata changes, and normalizes tags
+pub(crate) fn metadata_update(
+    tags: Option<&[String]>,
+    mood: Option<Option<u8>>,
+    location: Option<Option<String>>,
+) -> Option<MetadataUpdate> {
+    let tags = match tags {
+        None => None,
+        Some(x) => Some(normalize_tags(x)?),
+    };
+    if mood.flatten().is_some_and(|x| !is_valid_mood(x))
+        || location
+            .as_ref()
+            .and_then(|x| x.as_deref())
+            .is_some_and(|x| !is_valid_location(x))
+    {
+        return None;
+    }
+    Some(MetadataUpdate {
+        tags,
+        mood,
+        location,
+    })
+}
+
+/// Entries in encrypted diary books must be encrypted with a key of the
+/// user, and entries in other books must not be encrypted
+pub(crate) fn check_encryption(
+    db: &Database,
+    book_id: u64,
+    user_id: u64,
+    encryption: Option<&Encryption>,
+) -> rusqlite::Result<bool> {
+    Ok(match encryption {
+        None => !db.is_book_encrypted(book_id)?,
+        Some(x) => {
+            x.is_valid()
+                && db.is_book_encrypted(book_id)?
+                && db.check_user_key(user_id, &x.key_id)?
+        }
+    })
+}
+
+/// Private-use characters the database puts around matched text in snippets,
+/// replaced by the requested markers after escaping
+pub(crate) const SNIPPET_MARK_START: char = '\u{E000}';
+pub(crate) const SNIPPET_MARK_END: char = '\u{E001}';
+
+/// Tags allowed as search snippet markers, e.g. `<b>` and `</b>`
+const MARKER_TAGS: [&str; 6] = ["mark", "b", "strong", "em", "i", "u"];
+
+/// Keeps opening and closing tags in [`MARKER_TAGS`]; other markers are
+/// HTML-escaped
+fn sanitize_marker(marker: &str) -> String {
+    let name = marker
+        .strip_prefix("</")
+        .or_else(|| marker.strip_prefix('<'))
+        .and_then(|x| x.strip_suffix('>'));
+    match name {
+        Some(x) if MARKER_TAGS.contains(&x) => marker.to_string(),
+        _ => marker.chars().fold(String::new(), |mut result, c| {
+            push_escaped(&mut result, c);
+            result
+        }),
+    }
+}
+
+/// HTML-escapes a snippet marked with [`SNIPPET_MARK_START`] and
+/// [`SNIPPET_MARK_END`], and replaces them with the requested markers
+pub(crate) fn highlight_snippet(snippet: &str, mark_start: &str, mark_end: &str) -> String {
+    let mut result = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            SNIPPET_MARK_START => result.push_str(mark_start),
+            SNIPPET_MARK_END => result.push_str(mark_end),
+            _ => push_escaped(&mut result, c),
+        }
+    }
+    result
+}
+
+fn push_escaped(result: &mut String, c: char) {
+    match c {
+        '&' => result.push_str("&amp;"),
+        '<' => result.push_str("&lt;"),
+        '>' => result.push_str("&gt;"),
+        '"' => result.push_str("&quot;"),
+        '\'' => result.push_str("&#39;"),
+        _ => result.push(c),
+    }
+}
+
+/// Converts user input to a search filter; all whitespace-separated terms
+/// must match.
+///
+/// Terms are quoted so that the input can't contain FTS5 syntax. Terms too
+/// short for the trigram tokenizer are matched with `LIKE` instead.
+///
+/// Returns: (FTS5 query, `LIKE` patterns), or `None` if there are no terms
+fn parse_search_terms(input: &str) -> Option<(Option<String>, Vec<String>)> {
+    let (long, short): (Vec<_>, Vec<_>) = input
+        .split_whitespace()
+        .partition(|x| x.chars().count() >= MIN_SEARCH_TERM_LENGTH);
+    if long.is_empty() && short.is_empty() {
+        return None;
+    }
+
+    let match_query = (!long.is_empty()).then(|| {
+        long.iter()
+            .map(|x| format!("\"{}\"", x.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ")
+    });
+    let like_patterns = short
+        .iter()
+        .map(|x| {
+            let escaped = x
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{}%", escaped)
+        })
+        .collect();
+    Some((match_query, like_patterns))
+}
+
+pub async fn fetch(cookies: CookieJar, Query(query): Query<FetchQuery>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        if let Err(status) = check_role(
+            db.query_book_role(query.book_id, claims.user_id)?,
+            Role::Viewer,
+        ) {
+            return Ok(failure_response(status).into_response());
+        }
+
+        Ok(match db.fetch_diary(query.book_id, query.date)? {
+            None => failure_response(ResponseStatus::NoRecord).into_response(),
+            Some(x) => (etag_header(entity_tag(&x)), ResponseJson::ok(x)).into_response(),
+        })
+    })
+    .await
+    .into_response()
+}
+
+/// Creates the entry of the date in the diary book if it doesn't exist,
+/// otherwise replaces its content
+///
+/// Returns: id of the entry
+pub async fn update(
+    cookies: CookieJar,
+    headers: HeaderMap,
+    Json(form): Json<UpdateForm>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    if !is_valid_date(form.date) {
+        return failure_response(ResponseStatus::InvalidDate).into_response();
+    }
+    let Some(metadata) = metadata_update(form.tags.as_deref(), form.mood, form.location) else {
+        return failure_response(ResponseStatus::InvalidMetadata).into_response();
+    };
+
+    database::write(move |db| {
+        if let Err(status) = check_role(
+            db.query_book_role(form.book_id, claims.user_id)?,
+            Role::Editor,
+        ) {
+            return Ok(failure_response(status).into_response());
+        }
+        if !check_encryption(db, form.book_id, claims.user_id, form.encryption.as_ref())? {
+            return Ok(failure_response(ResponseStatus::InvalidEncryption).into_response());
+        }
+        let current = db.fetch_diary(form.book_id, form.date)?;
+        if let Some(x) = check_if_match(&headers, current.as_ref().map(entity_tag).as_deref()) {
+            return Ok(x);
+        }
+
+        let id = db.update_diary(
+            form.book_id,
+            form.date,
+            &form.content,
+            form.encryption.as_ref(),
+            &metadata,
+        )?;
+        let tag = db.fetch_diary_by_id(id)?.as_ref().map(entity_tag);
+        Ok((tag.map(etag_header), ResponseJson::ok(id)).into_response())
+    })
+    .await
+    .into_response()
+}
+
+pub async fn delete(
+    cookies: CookieJar,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::write(move |db| {
+        if let Err(status) = check_role(db.query_diary_role(id, claims.user_id)?, Role::Editor) {
+            return Ok(failure_response(status).into_response());
+        }
+        let current = db.fetch_diary_by_id(id)?;
+        if let Some(x) = check_if_match(&headers, current.as_ref().map(entity_tag).as_deref()) {
+            return Ok(x);
+        }
+
+        db.delete_diary(id)?;
+        attachment::remove_orphan_files(db)?;
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
+}
+
+pub async fn list(cookies: CookieJar, Query(query): Query<ListQuery>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        if let Err(status) = check_role(
+            db.query_book_role(query.book_id, claims.user_id)?,
+            Role::Viewer,
+        ) {
+            return Ok(failure_response(status).into_response());
+        }
+
+        let filter = EntryFilter {
+            tag: query.tag,
+            mood: query.mood,
+        };
+        let offset = query.offset.unwrap_or(0);
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+        let list = DiaryList {
+            total: db.count_diaries(query.book_id, &filter)?,
+            entries: db.list_diaries(query.book_id, &filter, offset, limit)?,
+        };
+        Ok(ResponseJson::ok(list).into_response())
+    })
+    .await
+    .into_response()
+}
+
+pub async fn search(cookies: CookieJar, Query(query): Query<SearchQuery>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    let Some((match_query, like_patterns)) = parse_search_terms(&query.q) else {
+        return failure_response(ResponseStatus::InvalidQuery).into_response();
+    };
+    if [query.from, query.to]
+        .into_iter()
+        .flatten()
+        .any(|x| !is_valid_date(x))
+    {
+        return failure_response(ResponseStatus::InvalidDate).into_response();
+    }
+
+    let filter = SearchFilter {
+        match_query,
+        like_patterns,
+        book_id: query.book_id,
+        from: query.from.unwrap_or(0),
+        to: query.to.unwrap_or(u32::MAX),
+        mark_start: query
+            .mark_start
+            .map_or_else(|| String::from("<mark>"), |x| sanitize_marker(&x)),
+        mark_end: query
+            .mark_end
+            .map_or_else(|| String::from("</mark>"), |x| sanitize_marker(&x)),
+    };
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+    database::read(move |db| {
+        let result = SearchResult {
+            total: db.count_search_results(claims.user_id, &filter)?,
+            hits: db.search_diaries(claims.user_id, &filter, offset, limit)?,
+        };
+        Ok(ResponseJson::ok(result).into_response())
+    })
+    .await
+    .into_response()
+}
+
+#[test]
+fn search_terms() {
+    let parse = |x| parse_search_terms(x).unwrap();
+    assert_eq!(
+        parse("  hello   world "),
+        (Some(r#""hello" "world""#.into()), vec![])
+    );
+    assert_eq!(
+        parse(r#"say "hi!" NOT"#),
+        (Some(r#""say" """hi!""" "NOT""#.into()), vec![])
+    );
+    assert_eq!(
+        parse("公园 _%"),
+        (None, vec!["%公园%".into(), r"%\_\%%".into()])
+    );
+    assert_eq!(
+        parse("今天天气 OR"),
+        (Some(r#""今天天气""#.into()), vec!["%OR%".into()])
+    );
+    assert_eq!(parse_search_terms(" "), None);
+
+    let snippet = format!(
+        "a <script>alert('x')</script> {}b&c{}",
+        SNIPPET_MARK_START, SNIPPET_MARK_END
+    );
+    assert_eq!(
+        highlight_snippet(&snippet, "<mark>", "</mark>"),
+        "a &lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; <mark>b&amp;c</mark>"
+    );
+    assert_eq!(sanitize_marker("<b>"), "<b>");
+    assert_eq!(sanitize_marker("</em>"), "</em>");
+    assert_eq!(sanitize_marker("**"), "**");
+    assert_eq!(
+        sanitize_marker("<img src=x onerror=alert(1)>"),
+        "&lt;img src=x onerror=alert(1)&gt;"
+    );
+    assert_eq!(sanitize_marker("<script>"), "&lt;script&gt;");
+}
+
+#[test]
+fn escaped_snippets() {
+    let db = Database::open_in_memory();
+    let user_id = db.add_user("user", "").unwrap();
+    let book_id = db.create_diary_book("book", user_id, false).unwrap();
+    let content = "<script>alert(1)</script> a day in the park";
+    db.update_diary(book_id, 20230415, content, None, &MetadataUpdate::default())
+        .unwrap();
+
+    let (match_query, like_patterns) = parse_search_terms("park").unwrap();
+    let filter = SearchFilter {
+        match_query,
+        like_patterns,
+        book_id: None,
+        from: 0,
+        to: u32::MAX,
+        mark_start: "<mark>".into(),
+        mark_end: "</mark>".into(),
+    };
+    let hits = db.search_diaries(user_id, &filter, 0, 10).unwrap();
+    assert_eq!(hits.len(), 1);
+    assert_eq!(
+        hits[0].snippet,
+        "&lt;script&gt;alert(1)&lt;/script&gt; a day in the <mark>park</mark>"
+    );
+
+    // the index follows content changes
+    db.update_diary(
+        book_id,
+        20230415,
+        "a day at home",
+        None,
+        &MetadataUpdate::default(),
+    )
+    .unwrap();
+    assert!(db
+        .search_diaries(user_id, &filter, 0, 10)
+        .unwrap()
+        .is_empty());
 }
@@ -0,0 +1,119 @@
+//! End-to-end encryption of diary books
+//!
+//! Clients encrypt entries of encrypted diary books, and the server only
+//! stores the ciphertext and its metadata. Diary keys are stored wrapped by
+//! a password-derived key, so that a new device can unlock them.
+
+use axum::extract::Path;
+use axum::response::IntoResponse;
+use axum::Json;
+use axum_extra::extract::CookieJar;
+use rusqlite::Row;
+use serde::{Deserialize, Serialize};
+
+use crate::routes::diary::database;
+use crate::routes::diary::{failure_response, ResponseStatus};
+use crate::{get_session, ResponseJson};
+
+const MAX_METADATA_LENGTH: usize = 256;
+const MAX_WRAPPED_KEY_LENGTH: usize = 4096;
+
+/// Metadata of an encrypted entry; opaque to the server
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Encryption {
+    /// id of the user key the entry is encrypted with
+    pub key_id: String,
+    pub nonce: String,
+    pub algorithm: String,
+}
+
+impl Encryption {
+    pub fn is_valid(&self) -> bool {
+        [&self.key_id, &self.nonce, &self.algorithm]
+            .iter()
+            .all(|x| !x.is_empty() && x.len() <= MAX_METADATA_LENGTH)
+    }
+
+    /// Reads the `key_id`, `nonce` and `algorithm` columns starting at `index`
+    pub(crate) fn from_row(row: &Row, index: usize) -> rusqlite::Result<Option<Self>> {
+        let key_id: Option<String> = row.get(index)?;
+        let nonce: Option<String> = row.get(index + 1)?;
+        let algorithm: Option<String> = row.get(index + 2)?;
+        Ok(match (key_id, nonce, algorithm) {
+            (Some(key_id), Some(nonce), Some(algorithm)) => Some(Self {
+                key_id,
+                nonce,
+                algorithm,
+            }),
+            _ => None,
+        })
+    }
+
+    /// Values of the `key_id`, `nonce` and `algorithm` columns
+    pub(crate) fn columns(encryption: Option<&Self>) -> [Option<&str>; 3] {
+        match encryption {
+            None => [None; 3],
+            Some(x) => [
+                Some(x.key_id.as_str()),
+                Some(x.nonce.as_str()),
+                Some(x.algorithm.as_str()),
+            ],
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserKey {
+    pub key_id: String,
+    pub wrapped_key: String,
+    pub params: String,
+    pub update_time: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyForm {
+    pub wrapped_key: String,
+    /// client-defined, e.g. KDF parameters and salt
+    pub params: String,
+}
+
+/// Lists the wrapped diary keys of the current user
+pub async fn list_keys(cookies: CookieJar) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        let keys = db.list_user_keys(claims.user_id)?;
+        Ok(ResponseJson::ok(keys).into_response())
+    })
+    .await
+    .into_response()
+}
+
+/// Uploads a wrapped diary key, or replaces it, e.g. after it's
+/// re-wrapped for a new password
+pub async fn put_key(
+    cookies: CookieJar,
+    Path(key_id): Path<String>,
+    Json(form): Json<KeyForm>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    if key_id.is_empty()
+        || key_id.len() > MAX_METADATA_LENGTH
+        || form.wrapped_key.is_empty()
+        || form.wrapped_key.len() > MAX_WRAPPED_KEY_LENGTH
+        || form.params.len() > MAX_WRAPPED_KEY_LENGTH
+    {
+        return failure_response(ResponseStatus::InvalidEncryption).into_response();
+    }
+
+    database::write(move |db| {
+        db.put_user_key(claims.user_id, &key_id, &form.wrapped_key, &form.params)?;
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
+}
@@ -0,0 +1,104 @@
+//! Optimistic concurrency control of diary entries, books and user profiles
+//!
+//! Responses of these resources carry an `ETag`, the BLAKE3 hash of the
+//! resource, and modifications honor `If-Match` so that edits from another
+//! device aren't silently overwritten.
+
+use axum::http::header::{ETAG, IF_MATCH};
+use axum::http::{HeaderMap, HeaderName, StatusCode};
+use axum::response::{IntoResponse, Response};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::routes::diary::{failure_response, ResponseStatus};
+use crate::{mutex_lock, CONFIG};
+
+static REQUIRE_IF_MATCH: Lazy<bool> = Lazy::new(|| {
+    let guard = mutex_lock!(CONFIG);
+    let config = guard.app.diary.as_ref().expect("Missing config");
+    config.require_if_match.unwrap_or(false)
+});
+
+/// Returns the quoted strong entity tag of the resource
+pub(crate) fn entity_tag<T: Serialize>(resource: &T) -> String {
+    let json = serde_json::to_vec(resource).expect("Serialization failed");
+    format!("\"{}\"", blake3::hash(&json).to_hex())
+}
+
+pub(crate) fn etag_header(tag: String) -> [(HeaderName, String); 1] {
+    [(ETAG, tag)]
+}
+
+/// `current` is the tag of the resource, or `None` if it doesn't exist.
+/// Weak tags never match, as required for `If-Match`.
+fn evaluate_if_match(
+    if_match: Option<&str>,
+    current: Option<&str>,
+    required: bool,
+) -> Result<(), ResponseStatus> {
+    let Some(if_match) = if_match else {
+        // creating a resource needs no precondition
+        return match required && current.is_some() {
+            true => Err(ResponseStatus::PreconditionRequired),
+            false => Ok(()),
+        };
+    };
+    let Some(current) = current else {
+        return Err(ResponseStatus::PreconditionFailed);
+    };
+    let matched = if_match
+        .split(',')
+        .map(str::trim)
+        .any(|x| x == "*" || x == current);
+    match matched {
+        true => Ok(()),
+        false => Err(ResponseStatus::PreconditionFailed),
+    }
+}
+
+/// Checks `If-Match` of the request against the tag of the resource to be
+/// modified, or `None` if it doesn't exist
+///
+/// Returns: the 412 or 428 response if the precondition isn't met
+pub(crate) fn check_if_match(headers: &HeaderMap, current: Option<&str>) -> Option<Response> {
+    // unreadable headers match no tag
+    let if_match = headers.get(IF_MATCH).map(|x| x.to_str().unwrap_or(""));
+    let status = evaluate_if_match(if_match, current, *REQUIRE_IF_MATCH).err()?;
+    let code = match status {
+        ResponseStatus::PreconditionRequired => StatusCode::PRECONDITION_REQUIRED,
+        _ => StatusCode::PRECONDITION_FAILED,
+    };
+    Some((code, failure_response(status)).into_response())
+}
+
+#[test]
+fn if_match() {
+    let tag = entity_tag(&("entry", 1));
+    assert_eq!(tag.len(), 66);
+    assert_ne!(tag, entity_tag(&("entry", 2)));
+
+    let current = Some(tag.as_str());
+    let listed = format!("\"0\", {}", tag);
+    let weak = format!("W/{}", tag);
+    assert_eq!(evaluate_if_match(None, current, false), Ok(()));
+    assert_eq!(
+        evaluate_if_match(None, current, true),
+        Err(ResponseStatus::PreconditionRequired)
+    );
+    assert_eq!(evaluate_if_match(None, None, true), Ok(()));
+    assert_eq!(evaluate_if_match(Some(&tag), current, true), Ok(()));
+    assert_eq!(evaluate_if_match(Some(&listed), current, false), Ok(()));
+    assert_eq!(evaluate_if_match(Some("*"), current, false), Ok(()));
+    assert_eq!(
+        evaluate_if_match(Some("*"), None, false),
+        Err(ResponseStatus::PreconditionFailed)
+    );
+    assert_eq!(
+        evaluate_if_match(Some(&weak), current, false),
+        Err(ResponseStatus::PreconditionFailed)
+    );
+    assert_eq!(
+        evaluate_if_match(Some("\"0\""), current, false),
+        Err(ResponseStatus::PreconditionFailed)
+    );
+}
@@ -0,0 +1,334 @@
+use std::collections::HashSet;
+use std::io::{Cursor, Write};
+
+use axum::extract::rejection::JsonRejection;
+use axum::extract::Query;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use axum_extra::extract::CookieJar;
+use serde::{Deserialize, Serialize};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::routes::diary::database;
+use crate::routes::diary::diary_book::normalize_book_name;
+use crate::routes::diary::diary_entry::{is_valid_location, is_valid_mood};
+use crate::routes::diary::encryption::Encryption;
+use crate::routes::diary::sharing::{check_role, Role};
+use crate::routes::diary::tag::normalize_tags;
+use crate::routes::diary::{failure_response, is_valid_date, timestamp, ResponseStatus};
+use crate::{get_session, mutex_lock, ResponseJson, CONFIG};
+
+/// Version of the JSON export format
+const EXPORT_VERSION: u32 = 1;
+/// 64 MiB
+const DEFAULT_MAX_IMPORT_SIZE: usize = 64 * 1024 * 1024;
+const JSON_FILE_NAME: &str = "diary.json";
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiaryExport {
+    pub version: u32,
+    pub export_time: u64,
+    pub books: Vec<BookExport>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookExport {
+    pub name: String,
+    pub creation_time: u64,
+    #[serde(default)]
+    pub encrypted: bool,
+    pub entries: Vec<EntryExport>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntryExport {
+    /// date integer, e.g. 20230415; named `id` in older exports
+    #[serde(alias = "id")]
+    pub date: u32,
+    pub content: String,
+    pub creation_time: u64,
+    #[serde(default)]
+    pub updated_time: Option<u64>,
+    #[serde(default)]
+    pub encryption: Option<Encryption>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub mood: Option<u8>,
+    #[serde(default)]
+    pub location: Option<String>,
+}
+
+#[derive(Deserialize, Copy, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// a single JSON file, which can be imported back
+    #[default]
+    Json,
+    /// a ZIP archive of Markdown files, one per date
+    Markdown,
+    /// a ZIP archive containing both the JSON file and Markdown files
+    Archive,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    /// exports all diary books if absent
+    pub book_id: Option<u64>,
+    pub format: Option<ExportFormat>,
+}
+
+#[derive(Deserialize, Copy, Clone, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    /// existing entries are kept
+    #[default]
+    Merge,
+    /// existing entries are replaced
+    Overwrite,
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    /// imports all entries into this diary book; otherwise books are matched
+    /// by name, and created if missing
+    pub book_id: Option<u64>,
+    pub mode: Option<ImportMode>,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportReport {
+    pub created: u32,
+    pub updated: u32,
+    pub unchanged: u32,
+}
+
+fn format_date(date: u32) -> String {
+    format!(
+        "{:04}-{:02}-{:02}",
+        date / 10000,
+        date / 100 % 100,
+        date % 100
+    )
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    match name.trim() {
+        "" | "." | ".." => String::from("untitled"),
+        x => x.to_string(),
+    }
+}
+
+fn render_markdown(entry: &EntryExport) -> String {
+    format!("# {}\n\n{}\n", format_date(entry.date), entry.content)
+}
+
+/// Layout: `<book name>/<yyyy-MM-dd>.md`, plus `diary.json` if `include_json` is set
+///
+/// Encrypted books are only included in `diary.json`, since the server
+/// can't decrypt them.
+fn write_archive(export: &DiaryExport, include_json: bool) -> zip::result::ZipResult<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    if include_json {
+        zip.start_file(JSON_FILE_NAME, options)?;
+        zip.write_all(&serde_json::to_vec_pretty(export).unwrap())?;
+    }
+
+    let mut directories = HashSet::new();
+    for book in export.books.iter().filter(|x| !x.encrypted) {
+        // books may have the same name
+        let name = sanitize_file_name(&book.name);
+        let mut directory = name.clone();
+        let mut n = 1;
+        while !directories.insert(directory.clone()) {
+            n += 1;
+            directory = format!("{} ({})", name, n);
+        }
+
+        for entry in &book.entries {
+            zip.start_file(
+                format!("{}/{}.md", directory, format_date(entry.date)),
+                options,
+            )?;
+            zip.write_all(render_markdown(entry).as_bytes())?;
+        }
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+pub async fn export(cookies: CookieJar, Query(query): Query<ExportQuery>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    let book_id = query.book_id;
+    let books = database::read(move |db| {
+        if let Some(id) = book_id {
+            if check_role(db.query_book_role(id, claims.user_id)?, Role::Viewer).is_err() {
+                return Ok(None);
+            }
+        }
+        Ok(Some(db.export_diary_books(claims.user_id, book_id)?))
+    })
+    .await;
+    let books = match books {
+        Ok(Some(x)) => x,
+        Ok(None) => return failure_response(ResponseStatus::NoRecord).into_response(),
+        Err(e) => return e.into_response(),
+    };
+
+    let export = DiaryExport {
+        version: EXPORT_VERSION,
+        export_time: timestamp(),
+        books,
+    };
+    let format = query.format.unwrap_or_default();
+    let (content_type, extension) = match format {
+        ExportFormat::Json => ("application/json", "json"),
+        ExportFormat::Markdown | ExportFormat::Archive => ("application/zip", "zip"),
+    };
+    // unwrap: writing to memory doesn't fail
+    let body = tokio::task::spawn_blocking(move || match format {
+        ExportFormat::Json => serde_json::to_vec_pretty(&export).unwrap(),
+        ExportFormat::Markdown => write_archive(&export, false).unwrap(),
+        ExportFormat::Archive => write_archive(&export, true).unwrap(),
+    })
+    .await
+    .expect("Export failed");
+
+    let file_name = format!(
+        "diary-{}.{}",
+        chrono::Local::now().format("%Y%m%d"),
+        extension
+    );
+    (
+        [
+            (header::CONTENT_TYPE, String::from(content_type)),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", file_name),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+pub(crate) fn max_import_size() -> usize {
+    let guard = mutex_lock!(CONFIG);
+    let config = guard.app.diary.as_ref().expect("Missing config");
+    config.max_import_size.unwrap_or(DEFAULT_MAX_IMPORT_SIZE)
+}
+
+/// Imports data in the JSON export format. Entries are matched by date.
+pub async fn import(
+    cookies: CookieJar,
+    Query(query): Query<ImportQuery>,
+    data: Result<Json<DiaryExport>, JsonRejection>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    let mut data = match data {
+        Ok(Json(x)) => x,
+        Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                failure_response(ResponseStatus::ImportTooLarge),
+            )
+                .into_response();
+        }
+        Err(_) => return failure_response(ResponseStatus::InvalidImport).into_response(),
+    };
+
+    if data.version != EXPORT_VERSION {
+        return failure_response(ResponseStatus::InvalidImport).into_response();
+    }
+    if data
+        .books
+        .iter()
+        .flat_map(|x| &x.entries)
+        .any(|x| !is_valid_date(x.date))
+    {
+        return failure_response(ResponseStatus::InvalidDate).into_response();
+    }
+    for book in data.books.iter_mut() {
+        let Some(name) = normalize_book_name(&book.name) else {
+            return failure_response(ResponseStatus::InvalidBookName).into_response();
+        };
+        book.name = name;
+    }
+    let valid_encryption = data.books.iter().all(|book| {
+        book.entries.iter().all(|x| match &x.encryption {
+            None => !book.encrypted,
+            Some(e) => book.encrypted && e.is_valid(),
+        })
+    });
+    if !valid_encryption {
+        return failure_response(ResponseStatus::InvalidEncryption).into_response();
+    }
+    for entry in data.books.iter_mut().flat_map(|x| &mut x.entries) {
+        let Some(tags) = normalize_tags(&entry.tags) else {
+            return failure_response(ResponseStatus::InvalidMetadata).into_response();
+        };
+        entry.tags = tags;
+        if entry.mood.is_some_and(|x| !is_valid_mood(x))
+            || entry
+                .location
+                .as_deref()
+                .is_some_and(|x| !is_valid_location(x))
+        {
+            return failure_response(ResponseStatus::InvalidMetadata).into_response();
+        }
+    }
+    let overwrite = query.mode.unwrap_or_default() == ImportMode::Overwrite;
+
+    database::write(move |db| {
+        if let Some(id) = query.book_id {
+            if let Err(status) = check_role(db.query_book_role(id, claims.user_id)?, Role::Editor) {
+                return Ok(failure_response(status).into_response());
+            }
+            let encrypted = db.is_book_encrypted(id)?;
+            if data.books.iter().any(|x| x.encrypted != encrypted) {
+                return Ok(failure_response(ResponseStatus::InvalidEncryption).into_response());
+            }
+        }
+        for key_id in data
+            .books
+            .iter()
+            .flat_map(|x| &x.entries)
+            .filter_map(|x| x.encryption.as_ref().map(|x| &x.key_id))
+            .collect::<HashSet<_>>()
+        {
+            if !db.check_user_key(claims.user_id, key_id)? {
+                return Ok(failure_response(ResponseStatus::InvalidEncryption).into_response());
+            }
+        }
+
+        let report = db.import_diaries(claims.user_id, &data.books, query.book_id, overwrite)?;
+        Ok(ResponseJson::ok(report).into_response())
+    })
+    .await
+    .into_response()
+}
+
+#[test]
+fn archive_file_names() {
+    assert_eq!(format_date(20230415), "2023-04-15");
+    assert_eq!(sanitize_file_name("a/b:c"), "a_b_c");
+    assert_eq!(sanitize_file_name(" .. "), "untitled");
+}
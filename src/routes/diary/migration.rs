@@ -0,0 +1,199 @@
+//! Versioned schema migrations for the diary database
+//!
+//! The schema version is tracked by `PRAGMA user_version`. Each migration step
+//! bumps it by one and is applied in its own transaction.
+
+use anyhow::anyhow;
+use rusqlite::Connection;
+
+use crate::{mutex_lock, CONFIG};
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+macro_rules! migrations {
+    ($($version:literal => $name:literal),* $(,)?) => {
+        &[$(Migration {
+            version: $version,
+            name: $name,
+            sql: include_str!(concat!("./migrations/", $name, ".sql")),
+        }),*]
+    };
+}
+
+/// Ordered by version; versions start from 1 and must be contiguous
+pub static MIGRATIONS: &[Migration] = migrations![
+    1 => "001-initial",
+    2 => "002-single-row-info",
+    3 => "003-session",
+    4 => "004-diary-fts",
+    5 => "005-diary-revision",
+    6 => "006-diary-encryption",
+    7 => "007-book-sharing",
+    8 => "008-diary-metadata",
+    9 => "009-attachment",
+    10 => "010-user-admin",
+    11 => "011-invite-code",
+    12 => "012-per-book-entry",
+    13 => "013-diary-sync",
+    14 => "014-two-factor",
+    15 => "015-api-token",
+    16 => "016-diary-fts-update",
+    17 => "017-diary-autoincrement",
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|x| x.version).unwrap_or(0)
+}
+
+pub fn schema_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.pragma_query_value(None, "user_version", |r| r.get(0))
+}
+
+/// Returns migrations not yet applied to the database
+pub fn pending_migrations(conn: &Connection) -> anyhow::Result<&'static [Migration]> {
+    let version = schema_version(conn)?;
+    let latest = latest_version();
+    if version > latest {
+        return Err(anyhow!(
+            "Database schema version {} is newer than the supported version {}",
+            version,
+            latest
+        ));
+    }
+    Ok(&MIGRATIONS[version as usize..])
+}
+
+/// Applies all pending migrations
+///
+/// Foreign keys aren't enforced while migrating, so that tables can be
+/// rebuilt; instead, each migration is checked for violations before it's
+/// committed.
+///
+/// Returns: the new schema version
+pub fn migrate(conn: &mut Connection) -> anyhow::Result<u32> {
+    let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |r| r.get(0))?;
+    // only takes effect outside of transactions
+    conn.pragma_update(None, "foreign_keys", false)?;
+    let result = apply_migrations(conn);
+    conn.pragma_update(None, "foreign_keys", foreign_keys)?;
+    result?;
+    Ok(schema_version(conn)?)
+}
+
+fn apply_migrations(conn: &mut Connection) -> anyhow::Result<()> {
+    for m in pending_migrations(conn)? {
+        let transaction = conn.transaction()?;
+        transaction.execute_batch(m.sql)?;
+        let violations: u32 =
+            transaction.query_row("SELECT COUNT() FROM pragma_foreign_key_check", [], |r| {
+                r.get(0)
+            })?;
+        if violations != 0 {
+            return Err(anyhow!(
+                "Migration {} violates foreign key constraints",
+                m.name
+            ));
+        }
+        transaction.pragma_update(None, "user_version", m.version)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+/// Entry for the `migrate` CLI subcommand
+pub fn run_command(dry_run: bool) -> anyhow::Result<()> {
+    let database_file = mutex_lock!(CONFIG)
+        .app
+        .diary
+        .as_ref()
+        .ok_or_else(|| anyhow!("Missing diary config"))?
+        .database_file
+        .clone();
+
+    let mut conn = Connection::open(database_file)?;
+    let pending = pending_migrations(&conn)?;
+    println!(
+        "Schema version: {} (latest: {})",
+        schema_version(&conn)?,
+        latest_version()
+    );
+    if pending.is_empty() {
+        println!("No pending migrations");
+        return Ok(());
+    }
+    println!("Pending migrations:");
+    for m in pending {
+        println!("    {}: {}", m.version, m.name);
+    }
+    if dry_run {
+        return Ok(());
+    }
+
+    let version = migrate(&mut conn)?;
+    println!("Migrated to version {}", version);
+    Ok(())
+}
+
+#[test]
+fn contiguous_versions() {
+    for (i, m) in MIGRATIONS.iter().enumerate() {
+        assert_eq!(m.version as usize, i + 1);
+    }
+}
+
+#[test]
+fn migrate_legacy_database() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    // databases created before migrations were introduced
+    conn.execute_batch(MIGRATIONS[0].sql).unwrap();
+    for _ in 0..3 {
+        conn.execute("INSERT INTO info VALUES ('')", []).unwrap();
+    }
+    // entries were keyed by their dates
+    conn.execute_batch(
+        "INSERT INTO diary_book VALUES (1, 'b', 0);
+INSERT INTO diary VALUES (20230415, 'a', 0);
+INSERT INTO mapping_diary_book_diary_entry VALUES (1, 20230415);",
+    )
+    .unwrap();
+
+    assert_eq!(migrate(&mut conn).unwrap(), latest_version());
+    let count: u32 = conn
+        .query_row("SELECT COUNT() FROM info", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(count, 1);
+    let date: u32 = conn
+        .query_row(
+            "SELECT date FROM mapping_diary_book_diary_entry WHERE diary_id = 20230415",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(date, 20230415);
+    assert!(pending_migrations(&conn).unwrap().is_empty());
+
+    // ids of deleted entries aren't reused
+    let foreign_keys: bool = conn
+        .query_row("PRAGMA foreign_keys", [], |r| r.get(0))
+        .unwrap();
+    assert!(foreign_keys);
+    conn.execute_batch(
+        "DELETE FROM mapping_diary_book_diary_entry;
+DELETE FROM diary;
+INSERT INTO diary (content, creation_time) VALUES ('b', 0);",
+    )
+    .unwrap();
+    assert_eq!(conn.last_insert_rowid(), 20230416);
+    let matched: u32 = conn
+        .query_row(
+            "SELECT COUNT() FROM diary_fts WHERE diary_fts MATCH 'b'",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+    assert_eq!(matched, 0);
+}
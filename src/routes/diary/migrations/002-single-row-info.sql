@@ -0,0 +1,17 @@
+-- Flavor: SQLite3
+
+-- previous versions inserted a new row into `info` on every start;
+-- only keep one row and enforce this with the `id` constraint
+CREATE TABLE info_new
+(
+    id   INTEGER NOT NULL PRIMARY KEY CHECK (id = 0),
+    json TEXT    NOT NULL
+);
+
+INSERT INTO info_new (id, json)
+VALUES (0, COALESCE((SELECT json FROM info WHERE json != '' LIMIT 1), '{}'));
+
+DROP TABLE info;
+
+ALTER TABLE info_new
+    RENAME TO info;
@@ -0,0 +1,18 @@
+-- Flavor: SQLite3
+
+-- login sessions; access tokens carry the session id and are only valid
+-- while the session exists
+CREATE TABLE session
+(
+    id                 INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id            INTEGER NOT NULL,
+    -- BLAKE3 hash of the refresh token, hex-encoded
+    refresh_token_hash TEXT    NOT NULL UNIQUE,
+    -- UNIX timestamp in seconds
+    creation_time      INTEGER NOT NULL,
+    -- UNIX timestamp in seconds; expiration of the refresh token
+    expiration_time    INTEGER NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES user (id)
+);
+
+CREATE INDEX session_user_id ON session (user_id);
@@ -0,0 +1,36 @@
+-- Flavor: SQLite3
+
+-- full-text index over diary contents; the trigram tokenizer also works for
+-- text without word separators, e.g. Chinese
+CREATE VIRTUAL TABLE diary_fts USING fts5
+(
+    content,
+    content = 'diary',
+    content_rowid = 'id',
+    tokenize = 'trigram'
+);
+
+CREATE TRIGGER diary_fts_insert
+    AFTER INSERT
+    ON diary
+BEGIN
+    INSERT INTO diary_fts (rowid, content) VALUES (new.id, new.content);
+END;
+
+CREATE TRIGGER diary_fts_delete
+    AFTER DELETE
+    ON diary
+BEGIN
+    INSERT INTO diary_fts (diary_fts, rowid, content) VALUES ('delete', old.id, old.content);
+END;
+
+CREATE TRIGGER diary_fts_update
+    AFTER UPDATE
+    ON diary
+BEGIN
+    INSERT INTO diary_fts (diary_fts, rowid, content) VALUES ('delete', old.id, old.content);
+    INSERT INTO diary_fts (rowid, content) VALUES (new.id, new.content);
+END;
+
+-- index existing entries
+INSERT INTO diary_fts (diary_fts) VALUES ('rebuild');
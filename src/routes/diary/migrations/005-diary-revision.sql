@@ -0,0 +1,31 @@
+-- Flavor: SQLite3
+
+-- previous versions of diary entries; a row is recorded whenever the
+-- content of an entry is replaced
+CREATE TABLE diary_revision
+(
+    id            INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    diary_id      INTEGER NOT NULL,
+    content       TEXT    NOT NULL,
+    -- UNIX timestamp in seconds; when this version was replaced
+    revision_time INTEGER NOT NULL,
+    FOREIGN KEY (diary_id) REFERENCES diary (id)
+);
+
+CREATE INDEX diary_revision_diary_id ON diary_revision (diary_id);
+
+CREATE TRIGGER diary_revision_update
+    AFTER UPDATE OF content
+    ON diary
+    WHEN old.content IS NOT new.content
+BEGIN
+    INSERT INTO diary_revision (diary_id, content, revision_time)
+    VALUES (old.id, old.content, unixepoch());
+END;
+
+CREATE TRIGGER diary_revision_delete
+    AFTER DELETE
+    ON diary
+BEGIN
+    DELETE FROM diary_revision WHERE diary_id = old.id;
+END;
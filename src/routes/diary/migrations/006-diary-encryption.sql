@@ -0,0 +1,49 @@
+-- Flavor: SQLite3
+
+-- entries of encrypted diary books are encrypted by clients; the server
+-- stores the ciphertext in `content` along with the metadata below, and
+-- never interprets them
+ALTER TABLE diary_book
+    ADD COLUMN encrypted INTEGER NOT NULL DEFAULT 0;
+
+-- all NULL for plaintext entries
+ALTER TABLE diary
+    ADD COLUMN key_id TEXT;
+ALTER TABLE diary
+    ADD COLUMN nonce TEXT;
+ALTER TABLE diary
+    ADD COLUMN algorithm TEXT;
+
+ALTER TABLE diary_revision
+    ADD COLUMN key_id TEXT;
+ALTER TABLE diary_revision
+    ADD COLUMN nonce TEXT;
+ALTER TABLE diary_revision
+    ADD COLUMN algorithm TEXT;
+
+DROP TRIGGER diary_revision_update;
+
+CREATE TRIGGER diary_revision_update
+    AFTER UPDATE OF content
+    ON diary
+    WHEN old.content IS NOT new.content
+BEGIN
+    INSERT INTO diary_revision (diary_id, content, revision_time, key_id, nonce, algorithm)
+    VALUES (old.id, old.content, unixepoch(), old.key_id, old.nonce, old.algorithm);
+END;
+
+-- diary keys of users, wrapped (encrypted) by clients with a key derived
+-- from the password, so they can be unlocked on other devices
+CREATE TABLE user_key
+(
+    user_id       INTEGER NOT NULL,
+    -- chosen by the client
+    key_id        TEXT    NOT NULL,
+    wrapped_key   TEXT    NOT NULL,
+    -- client-defined, e.g. KDF parameters and salt
+    params        TEXT    NOT NULL,
+    -- UNIX timestamp in seconds
+    update_time   INTEGER NOT NULL,
+    PRIMARY KEY (user_id, key_id),
+    FOREIGN KEY (user_id) REFERENCES user (id)
+);
@@ -0,0 +1,29 @@
+-- Flavor: SQLite3
+
+-- diary books can be shared with other users; `book_id` is no longer unique
+CREATE TABLE mapping_user_diary_book_new
+(
+    user_id  INTEGER NOT NULL,
+    book_id  INTEGER NOT NULL,
+    -- 0: owner, 1: editor, 2: viewer
+    role     INTEGER NOT NULL DEFAULT 0,
+    -- 0 for pending invitations
+    accepted INTEGER NOT NULL DEFAULT 1,
+    PRIMARY KEY (user_id, book_id),
+    FOREIGN KEY (book_id) REFERENCES diary_book (id),
+    FOREIGN KEY (user_id) REFERENCES user (id)
+);
+
+INSERT INTO mapping_user_diary_book_new (user_id, book_id, role, accepted)
+SELECT user_id, book_id, 0, 1
+FROM mapping_user_diary_book;
+
+DROP TABLE mapping_user_diary_book;
+
+ALTER TABLE mapping_user_diary_book_new
+    RENAME TO mapping_user_diary_book;
+
+CREATE INDEX mapping_user_diary_book_book_id ON mapping_user_diary_book (book_id);
+
+-- each book has exactly one owner
+CREATE UNIQUE INDEX mapping_user_diary_book_owner ON mapping_user_diary_book (book_id) WHERE role = 0;
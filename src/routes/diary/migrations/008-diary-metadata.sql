@@ -0,0 +1,39 @@
+-- Flavor: SQLite3
+
+-- 1 to 5; NULL if unset
+ALTER TABLE diary
+    ADD COLUMN mood INTEGER;
+ALTER TABLE diary
+    ADD COLUMN location TEXT;
+-- UNIX timestamp in seconds
+ALTER TABLE diary
+    ADD COLUMN updated_time INTEGER NOT NULL DEFAULT 0;
+
+UPDATE diary
+SET updated_time = creation_time;
+
+CREATE TABLE tag
+(
+    id   INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    name TEXT    NOT NULL UNIQUE
+);
+
+CREATE TABLE diary_tag
+(
+    diary_id INTEGER NOT NULL,
+    tag_id   INTEGER NOT NULL,
+    PRIMARY KEY (diary_id, tag_id),
+    FOREIGN KEY (diary_id) REFERENCES diary (id),
+    FOREIGN KEY (tag_id) REFERENCES tag (id)
+);
+
+CREATE INDEX diary_tag_tag_id ON diary_tag (tag_id);
+
+CREATE INDEX diary_mood ON diary (mood);
+
+CREATE TRIGGER diary_tag_delete
+    AFTER DELETE
+    ON diary
+BEGIN
+    DELETE FROM diary_tag WHERE diary_id = old.id;
+END;
@@ -0,0 +1,43 @@
+-- Flavor: SQLite3
+
+-- files attached to diary entries; contents are stored in the attachment
+-- directory, named by their hashes, so identical files are stored once
+CREATE TABLE attachment
+(
+    id            INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    diary_id      INTEGER NOT NULL,
+    -- BLAKE3 hash of the content, hex-encoded
+    hash          TEXT    NOT NULL,
+    file_name     TEXT    NOT NULL,
+    mime_type     TEXT    NOT NULL,
+    -- in bytes
+    size          INTEGER NOT NULL,
+    -- UNIX timestamp in seconds
+    creation_time INTEGER NOT NULL,
+    FOREIGN KEY (diary_id) REFERENCES diary (id)
+);
+
+CREATE INDEX attachment_diary_id ON attachment (diary_id);
+CREATE INDEX attachment_hash ON attachment (hash);
+
+-- hashes no longer referenced by any attachment; their files are removed
+-- by the server
+CREATE TABLE attachment_orphan
+(
+    hash TEXT NOT NULL PRIMARY KEY
+);
+
+CREATE TRIGGER attachment_diary_delete
+    AFTER DELETE
+    ON diary
+BEGIN
+    DELETE FROM attachment WHERE diary_id = old.id;
+END;
+
+CREATE TRIGGER attachment_delete
+    AFTER DELETE
+    ON attachment
+    WHEN NOT EXISTS(SELECT 1 FROM attachment WHERE hash = old.hash)
+BEGIN
+    INSERT OR IGNORE INTO attachment_orphan (hash) VALUES (old.hash);
+END;
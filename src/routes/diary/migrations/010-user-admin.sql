@@ -0,0 +1,10 @@
+-- Flavor: SQLite3
+
+-- administrators manage other users; bootstrapped by the `admins` config
+-- or the `admin` CLI subcommand
+ALTER TABLE user
+    ADD is_admin INTEGER NOT NULL DEFAULT 0;
+
+-- disabled users can't log in, and their sessions are rejected
+ALTER TABLE user
+    ADD disabled INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,19 @@
+-- Flavor: SQLite3
+
+-- single-use codes for signing up when open signup is disabled
+CREATE TABLE invite_code
+(
+    code            TEXT    NOT NULL PRIMARY KEY,
+    -- the admin who generated the code
+    created_by      INTEGER NOT NULL,
+    -- UNIX timestamp in seconds
+    creation_time   INTEGER NOT NULL,
+    -- UNIX timestamp in seconds; never expires if null
+    expiration_time INTEGER,
+    -- null until the code is used
+    used_by         INTEGER,
+    -- UNIX timestamp in seconds
+    used_time       INTEGER,
+    FOREIGN KEY (created_by) REFERENCES user (id),
+    FOREIGN KEY (used_by) REFERENCES user (id)
+);
@@ -0,0 +1,24 @@
+-- Flavor: SQLite3
+
+-- entries used to be keyed by their dates across the whole server; now
+-- each entry has its own id, and dates are only unique within a book.
+-- Existing ids were dates, so they're kept as both.
+CREATE TABLE mapping_diary_book_diary_entry_new
+(
+    book_id  INTEGER NOT NULL,
+    diary_id INTEGER NOT NULL UNIQUE,
+    -- date integer, e.g. 20230415
+    date     INTEGER NOT NULL,
+    FOREIGN KEY (book_id) REFERENCES diary_book (id),
+    FOREIGN KEY (diary_id) REFERENCES diary (id),
+    UNIQUE (book_id, date)
+);
+
+INSERT INTO mapping_diary_book_diary_entry_new (book_id, diary_id, date)
+SELECT book_id, diary_id, diary_id
+FROM mapping_diary_book_diary_entry;
+
+DROP TABLE mapping_diary_book_diary_entry;
+
+ALTER TABLE mapping_diary_book_diary_entry_new
+    RENAME TO mapping_diary_book_diary_entry;
@@ -0,0 +1,33 @@
+-- Flavor: SQLite3
+
+-- Every change of an entry takes a number from this server-wide counter,
+-- so that clients can ask for the changes after the last one they've seen
+CREATE TABLE sync_counter
+(
+    seq INTEGER NOT NULL
+);
+
+INSERT INTO sync_counter (seq)
+VALUES (1);
+
+-- incremented on each change of the entry
+ALTER TABLE diary
+    ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+-- counter value of the last change
+ALTER TABLE diary
+    ADD COLUMN change_seq INTEGER NOT NULL DEFAULT 1;
+
+CREATE INDEX diary_change_seq ON diary (change_seq);
+
+-- deleted entries; a new entry of the same date continues the version
+CREATE TABLE diary_tombstone
+(
+    book_id       INTEGER NOT NULL,
+    -- date integer, e.g. 20230415
+    date          INTEGER NOT NULL,
+    version       INTEGER NOT NULL,
+    change_seq    INTEGER NOT NULL,
+    deletion_time INTEGER NOT NULL,
+    PRIMARY KEY (book_id, date),
+    FOREIGN KEY (book_id) REFERENCES diary_book (id)
+);
@@ -0,0 +1,26 @@
+-- Flavor: SQLite3
+
+-- TOTP secrets for two-factor authentication; the secret only takes effect
+-- once the user confirms it with a first code
+CREATE TABLE user_totp
+(
+    user_id       INTEGER NOT NULL PRIMARY KEY,
+    -- hex-encoded
+    secret        TEXT    NOT NULL,
+    enabled       INTEGER NOT NULL DEFAULT 0,
+    -- time step of the last accepted code, so that codes can't be replayed
+    last_step     INTEGER NOT NULL DEFAULT 0,
+    -- UNIX timestamp in seconds
+    creation_time INTEGER NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES user (id)
+);
+
+-- single-use codes for logging in without the authenticator
+CREATE TABLE recovery_code
+(
+    user_id INTEGER NOT NULL,
+    -- hex-encoded BLAKE3 hash of the normalized code
+    hash    TEXT    NOT NULL,
+    PRIMARY KEY (user_id, hash),
+    FOREIGN KEY (user_id) REFERENCES user (id)
+);
@@ -0,0 +1,20 @@
+-- Flavor: SQLite3
+
+-- personal access tokens for scripts, accepted as `Authorization: Bearer`
+CREATE TABLE api_token
+(
+    id              INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id         INTEGER NOT NULL,
+    name            TEXT    NOT NULL,
+    -- hex-encoded BLAKE3 hash of the token
+    token_hash      TEXT    NOT NULL UNIQUE,
+    -- 0: read-only, 1: read-write
+    scope           INTEGER NOT NULL,
+    -- UNIX timestamp in seconds
+    creation_time   INTEGER NOT NULL,
+    -- UNIX timestamp in seconds; never expires if null
+    expiration_time INTEGER,
+    -- UNIX timestamp in seconds, updated at most once a minute
+    last_used_time  INTEGER,
+    FOREIGN KEY (user_id) REFERENCES user (id)
+);
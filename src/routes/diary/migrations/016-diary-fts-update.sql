@@ -0,0 +1,13 @@
+-- Flavor: SQLite3
+
+-- the index only needs updating when the content changes, not on every
+-- version or metadata bump
+DROP TRIGGER diary_fts_update;
+
+CREATE TRIGGER diary_fts_update
+    AFTER UPDATE OF content
+    ON diary
+BEGIN
+    INSERT INTO diary_fts (diary_fts, rowid, content) VALUES ('delete', old.id, old.content);
+    INSERT INTO diary_fts (rowid, content) VALUES (new.id, new.content);
+END;
@@ -0,0 +1,98 @@
+-- Flavor: SQLite3
+
+-- entry ids are exposed to clients, so ids of deleted entries must never be
+-- reused; this needs `AUTOINCREMENT`, which requires rebuilding the table
+CREATE TABLE diary_new
+(
+    id            INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    content       TEXT    NOT NULL,
+    -- UNIX timestamp in seconds
+    creation_time INTEGER NOT NULL,
+    key_id        TEXT,
+    nonce         TEXT,
+    algorithm     TEXT,
+    mood          INTEGER,
+    location      TEXT,
+    updated_time  INTEGER NOT NULL DEFAULT 0,
+    -- incremented on each change of the entry
+    version       INTEGER NOT NULL DEFAULT 1,
+    -- counter value of the last change
+    change_seq    INTEGER NOT NULL DEFAULT 1
+);
+
+INSERT INTO diary_new (id, content, creation_time, key_id, nonce, algorithm, mood, location,
+                       updated_time, version, change_seq)
+SELECT id,
+       content,
+       creation_time,
+       key_id,
+       nonce,
+       algorithm,
+       mood,
+       location,
+       updated_time,
+       version,
+       change_seq
+FROM diary;
+
+-- triggers and indexes of the old table are dropped along with it; the
+-- deletion doesn't fire triggers, so the full-text index is kept
+DROP TABLE diary;
+
+ALTER TABLE diary_new
+    RENAME TO diary;
+
+CREATE INDEX diary_mood ON diary (mood);
+CREATE INDEX diary_change_seq ON diary (change_seq);
+
+CREATE TRIGGER diary_fts_insert
+    AFTER INSERT
+    ON diary
+BEGIN
+    INSERT INTO diary_fts (rowid, content) VALUES (new.id, new.content);
+END;
+
+CREATE TRIGGER diary_fts_delete
+    AFTER DELETE
+    ON diary
+BEGIN
+    INSERT INTO diary_fts (diary_fts, rowid, content) VALUES ('delete', old.id, old.content);
+END;
+
+CREATE TRIGGER diary_fts_update
+    AFTER UPDATE OF content
+    ON diary
+BEGIN
+    INSERT INTO diary_fts (diary_fts, rowid, content) VALUES ('delete', old.id, old.content);
+    INSERT INTO diary_fts (rowid, content) VALUES (new.id, new.content);
+END;
+
+CREATE TRIGGER diary_revision_update
+    AFTER UPDATE OF content
+    ON diary
+    WHEN old.content IS NOT new.content
+BEGIN
+    INSERT INTO diary_revision (diary_id, content, revision_time, key_id, nonce, algorithm)
+    VALUES (old.id, old.content, unixepoch(), old.key_id, old.nonce, old.algorithm);
+END;
+
+CREATE TRIGGER diary_revision_delete
+    AFTER DELETE
+    ON diary
+BEGIN
+    DELETE FROM diary_revision WHERE diary_id = old.id;
+END;
+
+CREATE TRIGGER diary_tag_delete
+    AFTER DELETE
+    ON diary
+BEGIN
+    DELETE FROM diary_tag WHERE diary_id = old.id;
+END;
+
+CREATE TRIGGER attachment_diary_delete
+    AFTER DELETE
+    ON diary
+BEGIN
+    DELETE FROM attachment WHERE diary_id = old.id;
+END;
@@ -1,39 +1,54 @@
-use std::sync::Mutex;
-
+use axum::extract::DefaultBodyLimit;
 use axum::response::IntoResponse;
-use axum::routing::{get, post};
-use axum::Router;
-use hex::ToHex;
+use axum::routing::{delete, get, post, put};
+use axum::{middleware, Router};
+use chrono::NaiveDate;
 use once_cell::sync::Lazy;
-use rand::distributions::Standard;
-use rand::rngs::OsRng;
-use rand::{Rng, RngCore};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-use crate::routes::diary::database::{Database, DatabaseInfo};
-use crate::{lazy_option_initializer, mutex_lock, LazyOption, ResponseJson, CONFIG};
+use crate::routes::diary::api_token::TokenScope;
+use crate::routes::diary::database::Pool;
+use crate::{mutex_lock, ResponseJson, CONFIG};
 
+pub mod admin;
+pub mod api_token;
+pub mod attachment;
 pub mod database;
 pub mod diary_book;
 pub mod diary_entry;
+pub mod encryption;
+pub mod etag;
+pub mod export;
+pub mod migration;
+pub mod revision;
 pub mod session;
+pub mod sharing;
+pub mod signup;
+pub mod stats;
+pub mod sync;
+pub mod tag;
+pub mod two_factor;
 pub mod user;
 
-static DATABASE_FILE: Lazy<String> = Lazy::new(|| {
-    mutex_lock!(CONFIG)
-        .app
-        .diary
-        .as_ref()
-        .expect("Missing config")
-        .database_file
-        .clone()
+const DEFAULT_DATABASE_READERS: usize = 4;
+
+static DATABASE: Lazy<Pool> = Lazy::new(|| {
+    let guard = mutex_lock!(CONFIG);
+    let config = guard.app.diary.as_ref().expect("Missing config");
+    let readers = config.database_readers.unwrap_or(DEFAULT_DATABASE_READERS);
+    let pool = Pool::open(&config.database_file, readers).unwrap();
+    for username in config.admins.iter().flatten() {
+        if !pool.set_admin(username, true).unwrap() {
+            eprintln!("Admin user doesn't exist: {}", username);
+        }
+    }
+    pool
 });
-static DATABASE: Lazy<Mutex<Database>> =
-    Lazy::new(|| Mutex::new(Database::new(&*DATABASE_FILE).unwrap()));
 
 #[derive(Deserialize)]
 pub struct FetchQuery {
-    pub diary_id: String,
+    pub book_id: u64,
+    /// date integer, e.g. 20230415
     pub date: u32,
 }
 
@@ -41,16 +56,44 @@ pub struct FetchQuery {
 pub struct AuthForm {
     pub username: String,
     pub password: String,
+    /// required if signup is invite-only
+    pub invite_code: Option<String>,
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum ResponseStatus {
     Ok = 0,
     UserExists,
     AuthenticationFailed,
     NoRecord,
     InvalidSession,
+    PermissionDenied,
+    InvalidDate,
+    BookNotEmpty,
+    InternalError,
+    InvalidUsername,
+    InvalidEmail,
+    InvalidQuery,
+    InvalidImport,
+    InvalidEncryption,
+    InvalidMetadata,
+    AttachmentTooLarge,
+    UnsupportedAttachmentType,
+    InvalidAttachment,
+    AccountDisabled,
+    SignupDisabled,
+    InvalidInviteCode,
+    WeakPassword,
+    TooManyRequests,
+    PreconditionFailed,
+    PreconditionRequired,
+    TwoFactorRequired,
+    InvalidTwoFactorCode,
+    TwoFactorEnabled,
+    InvalidTokenName,
+    InvalidBookName,
+    ImportTooLarge,
 }
 
 impl ResponseStatus {
@@ -61,6 +104,32 @@ impl ResponseStatus {
             ResponseStatus::AuthenticationFailed => "Authentication failed",
             ResponseStatus::NoRecord => "No record",
             ResponseStatus::InvalidSession => "Invalid session",
+            ResponseStatus::PermissionDenied => "Permission denied",
+            ResponseStatus::InvalidDate => "Invalid date",
+            ResponseStatus::BookNotEmpty => "Diary book is not empty",
+            ResponseStatus::InternalError => "Server internal error",
+            ResponseStatus::InvalidUsername => "Invalid username",
+            ResponseStatus::InvalidEmail => "Invalid email",
+            ResponseStatus::InvalidQuery => "Invalid search query",
+            ResponseStatus::InvalidImport => "Invalid import data",
+            ResponseStatus::InvalidEncryption => "Invalid encryption",
+            ResponseStatus::InvalidMetadata => "Invalid entry metadata",
+            ResponseStatus::AttachmentTooLarge => "Attachment is too large",
+            ResponseStatus::UnsupportedAttachmentType => "Unsupported attachment type",
+            ResponseStatus::InvalidAttachment => "Invalid attachment",
+            ResponseStatus::AccountDisabled => "Account is disabled",
+            ResponseStatus::SignupDisabled => "Signup is disabled",
+            ResponseStatus::InvalidInviteCode => "Invalid invite code",
+            ResponseStatus::WeakPassword => "Password is too weak",
+            ResponseStatus::TooManyRequests => "Too many requests",
+            ResponseStatus::PreconditionFailed => "Resource has been modified",
+            ResponseStatus::PreconditionRequired => "If-Match header is required",
+            ResponseStatus::TwoFactorRequired => "Two-factor authentication code is required",
+            ResponseStatus::InvalidTwoFactorCode => "Invalid two-factor authentication code",
+            ResponseStatus::TwoFactorEnabled => "Two-factor authentication is already enabled",
+            ResponseStatus::InvalidTokenName => "Invalid token name",
+            ResponseStatus::InvalidBookName => "Invalid diary book name",
+            ResponseStatus::ImportTooLarge => "Import data is too large",
         }
     }
 }
@@ -73,31 +142,47 @@ pub(crate) fn failure_response(status: ResponseStatus) -> impl IntoResponse {
 pub(crate) struct JwtClaims {
     username: String,
     user_id: u64,
+    /// session id
+    sid: u64,
     /// issued at
     iat: u64,
     /// expired at
     exp: u64,
+    /// id of the personal access token used instead of a session; never
+    /// part of JWTs
+    #[serde(skip)]
+    api_token: Option<u64>,
+    /// scope of `api_token`
+    #[serde(skip)]
+    api_token_scope: Option<TokenScope>,
 }
 
-fn random_salt(length: usize) -> String {
-    OsRng
-        .sample_iter::<u8, _>(Standard)
-        .map(char::from)
-        .filter(|x| x.is_ascii_alphanumeric() || x.is_ascii_punctuation())
-        .take(length)
-        .collect()
+/// Password hashing is CPU intensive, so it's run on the blocking thread pool
+pub(crate) async fn generate_password_hash(password: String) -> String {
+    tokio::task::spawn_blocking(move || crate::security::hash_password(&password))
+        .await
+        .expect("Password hashing failed")
 }
 
-const PASSWORD_SALT_LENGTH: usize = 16;
+pub fn init() {
+    if mutex_lock!(CONFIG).app.diary.is_some() {
+        Lazy::force(&DATABASE);
+    }
+}
 
-/// Returns: (password hash, associated salt string)
-pub(crate) fn generate_password_hash(password: &str) -> (String, String) {
-    let salt = random_salt(PASSWORD_SALT_LENGTH);
-    let hash = crate::security::hash_password(password, salt.as_bytes());
-    (hash, salt)
+/// Checks a date integer in the form of `yyyyMMdd`, e.g. 20230415
+pub(crate) fn is_valid_date(date: u32) -> bool {
+    NaiveDate::from_ymd_opt((date / 10000) as i32, date / 100 % 100, date % 100).is_some()
 }
 
-pub fn init() {}
+/// Distinguishes `null` (`Some(None)`) from absent fields (`None`)
+pub(crate) fn nullable<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
 
 /// Timestamp in seconds
 pub(crate) fn timestamp() -> u64 {
@@ -114,30 +199,92 @@ pub fn router() -> Router {
     }
     Router::new()
         /* --------------- user --------------- */
-        .route("/user", post(user::create_user).patch(user::update_user))
+        .route(
+            "/user",
+            post(user::create_user)
+                .patch(user::update_user)
+                .delete(user::delete_user),
+        )
+        .route("/user/password", put(user::change_password))
         .route("/user/:username", get(user::user_info))
         .route("/me", get(user::me_user_info))
+        .route(
+            "/user/2fa",
+            get(two_factor::status)
+                .post(two_factor::enroll)
+                .delete(two_factor::disable),
+        )
+        .route("/user/2fa/confirm", post(two_factor::confirm))
+        .route("/tokens", get(api_token::list).post(api_token::create))
+        .route("/token/:id", delete(api_token::revoke))
+        /* --------------- admin --------------- */
+        .route("/admin/users", get(admin::list_users))
+        .route("/admin/storage", get(admin::storage_usage))
+        .route("/admin/user/:username/disable", post(admin::disable_user))
+        .route("/admin/user/:username/enable", post(admin::enable_user))
+        .route("/admin/user/:username/password", put(admin::reset_password))
+        .route(
+            "/admin/invites",
+            get(admin::list_invite_codes).post(admin::create_invite_code),
+        )
+        .route("/admin/invite/:code", delete(admin::delete_invite_code))
         /* --------------- login --------------- */
-        .route("/session", post(session::login))
+        .route("/session", post(session::login).delete(session::logout))
+        .route("/session/refresh", post(session::refresh))
+        .route("/session/2fa", post(session::login_two_factor))
+        .route("/sessions", delete(session::logout_all))
         /* --------------- diary book --------------- */
         .route(
             "/book",
-            post(diary_book::create)
+            get(diary_book::fetch)
+                .post(diary_book::create)
                 .patch(diary_book::update)
                 .delete(diary_book::delete),
         )
         .route("/books", get(diary_book::list))
+        /* --------------- sharing --------------- */
+        .route("/book/:id/members", get(sharing::list_members))
+        .route(
+            "/book/:id/member/:username",
+            put(sharing::put_member).delete(sharing::delete_member),
+        )
+        .route("/invitations", get(sharing::list_invitations))
+        .route("/invitation/:book_id", post(sharing::accept_invitation))
         /* --------------- diary entry --------------- */
+        .route("/diary", get(diary_entry::fetch).put(diary_entry::update))
+        .route("/diary/stats", get(stats::stats))
+        .route("/diary/sync", post(sync::sync))
+        .route("/diary/:id", delete(diary_entry::delete))
+        .route("/diary/:id/revisions", get(revision::list))
+        .route("/diary/:id/revisions/diff", get(revision::diff))
+        .route("/diary/:id/revision/:revision", get(revision::fetch))
         .route(
-            "/diary/:id",
-            get(diary_entry::fetch).delete(diary_entry::delete),
+            "/diary/:id/revision/:revision/restore",
+            post(revision::restore),
         )
         .route("/diaries", get(diary_entry::list))
-}
-
-#[macro_export]
-macro_rules! lock_database {
-    () => {
-        crate::mutex_lock!(crate::routes::diary::DATABASE)
-    };
+        .route("/diaries/search", get(diary_entry::search))
+        .route("/tags", get(tag::tag_cloud))
+        /* --------------- attachment --------------- */
+        .route(
+            "/diary/:id/attachments",
+            get(attachment::list)
+                .post(attachment::upload)
+                // sizes are limited per file instead
+                .layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/attachment/:id",
+            get(attachment::download).delete(attachment::delete),
+        )
+        /* --------------- encryption --------------- */
+        .route("/keys", get(encryption::list_keys))
+        .route("/key/:key_id", put(encryption::put_key))
+        /* --------------- export --------------- */
+        .route("/diaries/export", get(export::export))
+        .route(
+            "/diaries/import",
+            post(export::import).layer(DefaultBodyLimit::max(export::max_import_size())),
+        )
+        .layer(middleware::from_fn(api_token::bearer_auth))
 }
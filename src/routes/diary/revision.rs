@@ -0,0 +1,237 @@
+use axum::extract::{Path, Query};
+use axum::response::IntoResponse;
+use axum_extra::extract::CookieJar;
+use serde::{Deserialize, Serialize};
+
+use crate::routes::diary::database;
+use crate::routes::diary::encryption::Encryption;
+use crate::routes::diary::sharing::{check_role, Role};
+use crate::routes::diary::{failure_response, ResponseStatus};
+use crate::{get_session, ResponseJson};
+
+/// Above this many cells in the LCS table, changed lines are reported as a
+/// whole deletion followed by a whole insertion
+const MAX_DIFF_CELLS: usize = 4 * 1024 * 1024;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevisionInfo {
+    pub id: u64,
+    pub diary_id: u64,
+    pub revision_time: u64,
+    /// length of the content in characters
+    pub length: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Revision {
+    pub id: u64,
+    pub diary_id: u64,
+    pub content: String,
+    /// when this version was replaced
+    pub revision_time: u64,
+    pub encryption: Option<Encryption>,
+}
+
+/// Both ends default to the current content of the entry
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    /// revision id
+    pub from: Option<u64>,
+    /// revision id
+    pub to: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Eq, PartialEq)]
+#[serde(tag = "op", content = "text", rename_all = "lowercase")]
+pub enum DiffLine<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+/// Line-based diff using the longest common subsequence
+fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+
+    let mut result = old[..prefix]
+        .iter()
+        .map(|x| DiffLine::Equal(x))
+        .collect::<Vec<_>>();
+
+    if (a.len() + 1) * (b.len() + 1) > MAX_DIFF_CELLS {
+        result.extend(a.iter().map(|x| DiffLine::Delete(x)));
+        result.extend(b.iter().map(|x| DiffLine::Insert(x)));
+    } else {
+        // lcs[i][j]: LCS length of a[i..] and b[j..]
+        let width = b.len() + 1;
+        let mut lcs = vec![0_u32; (a.len() + 1) * width];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i * width + j] = if a[i] == b[j] {
+                    lcs[(i + 1) * width + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() && j < b.len() {
+            if a[i] == b[j] {
+                result.push(DiffLine::Equal(a[i]));
+                i += 1;
+                j += 1;
+            } else if lcs[(i + 1) * width + j] >= lcs[i * width + j + 1] {
+                result.push(DiffLine::Delete(a[i]));
+                i += 1;
+            } else {
+                result.push(DiffLine::Insert(b[j]));
+                j += 1;
+            }
+        }
+        result.extend(a[i..].iter().map(|x| DiffLine::Delete(x)));
+        result.extend(b[j..].iter().map(|x| DiffLine::Insert(x)));
+    }
+
+    result.extend(old[old.len() - suffix..].iter().map(|x| DiffLine::Equal(x)));
+    result
+}
+
+/// Lists previous versions of an entry, latest first
+pub async fn list(cookies: CookieJar, Path(id): Path<u64>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        if let Err(status) = check_role(db.query_diary_role(id, claims.user_id)?, Role::Viewer) {
+            return Ok(failure_response(status).into_response());
+        }
+
+        let revisions = db.list_revisions(id)?;
+        Ok(ResponseJson::ok(revisions).into_response())
+    })
+    .await
+    .into_response()
+}
+
+pub async fn fetch(
+    cookies: CookieJar,
+    Path((id, revision)): Path<(u64, u64)>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        if let Err(status) = check_role(db.query_diary_role(id, claims.user_id)?, Role::Viewer) {
+            return Ok(failure_response(status).into_response());
+        }
+
+        Ok(match db.fetch_revision(id, revision)? {
+            None => failure_response(ResponseStatus::NoRecord).into_response(),
+            Some(x) => ResponseJson::ok(x).into_response(),
+        })
+    })
+    .await
+    .into_response()
+}
+
+/// Compares two versions of an entry line by line
+pub async fn diff(
+    cookies: CookieJar,
+    Path(id): Path<u64>,
+    Query(query): Query<DiffQuery>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        let Some(book_id) = db.query_diary_book(id)? else {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        };
+        if let Err(status) = check_role(db.query_book_role(book_id, claims.user_id)?, Role::Viewer)
+        {
+            return Ok(failure_response(status).into_response());
+        }
+        // ciphertext can't be compared; clients diff decrypted revisions themselves
+        if db.is_book_encrypted(book_id)? {
+            return Ok(failure_response(ResponseStatus::InvalidEncryption).into_response());
+        }
+
+        let mut contents = Vec::with_capacity(2);
+        for revision in [query.from, query.to] {
+            let content = match revision {
+                Some(x) => db.fetch_revision(id, x)?.map(|x| x.content),
+                None => db.fetch_diary_by_id(id)?.map(|x| x.content),
+            };
+            let Some(content) = content else {
+                return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+            };
+            contents.push(content);
+        }
+
+        let diff = diff_lines(&contents[0], &contents[1]);
+        Ok(ResponseJson::ok(diff).into_response())
+    })
+    .await
+    .into_response()
+}
+
+/// Replaces the content of the entry with a previous version; the
+/// replaced content is kept as a new revision
+pub async fn restore(
+    cookies: CookieJar,
+    Path((id, revision)): Path<(u64, u64)>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::write(move |db| {
+        if let Err(status) = check_role(db.query_diary_role(id, claims.user_id)?, Role::Editor) {
+            return Ok(failure_response(status).into_response());
+        }
+
+        if !db.restore_revision(id, revision)? {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        }
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
+}
+
+#[test]
+fn line_diff() {
+    use DiffLine::*;
+
+    assert_eq!(
+        diff_lines("a\nb\nc", "a\nb\nc"),
+        [Equal("a"), Equal("b"), Equal("c")]
+    );
+    assert_eq!(
+        diff_lines("a\nb\nc\nd", "a\nx\nc\nd\ne"),
+        [
+            Equal("a"),
+            Delete("b"),
+            Insert("x"),
+            Equal("c"),
+            Equal("d"),
+            Insert("e")
+        ]
+    );
+    assert_eq!(
+        diff_lines("b\nc", "a\nb\nc"),
+        [Insert("a"), Equal("b"), Equal("c")]
+    );
+    assert_eq!(diff_lines("a", ""), [Delete("a")]);
+}
@@ -1,41 +1,203 @@
-use axum::headers::{Header, HeaderValue, SetCookie};
+use axum::headers::SetCookie;
+use axum::http::StatusCode;
 use axum::response::{Html, IntoResponse};
 use axum::{Form, TypedHeader};
 use axum_extra::extract::CookieJar;
-use chrono::Duration;
-use jsonwebtoken::{Algorithm, EncodingKey};
+use hex::ToHex;
+use once_cell::sync::Lazy;
+use rand::rngs::OsRng;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 
-use crate::routes::demo::authentication::jwt_secret;
-use crate::routes::diary::{failure_response, AuthForm, JwtClaims, ResponseStatus};
-use crate::security::resolve_jwt;
-use crate::{lock_database, ResponseJson};
+use crate::routes::diary::api_token::{
+    check_scope, is_api_token, require_login_session, resolve_api_token,
+};
+use crate::routes::diary::database;
+use crate::routes::diary::database::{Credential, Database};
+use crate::routes::diary::two_factor::{verify_code, CodeForm, TWO_FACTOR_LIMITER};
+use crate::routes::diary::{failure_response, timestamp, JwtClaims, ResponseStatus};
+use crate::security::{
+    build_cookie, decode_jwt, encode_jwt, hash_password, resolve_jwt, set_cookies, verify_password,
+    PasswordVerification,
+};
+use crate::ResponseJson;
 
-#[inline]
-pub(crate) fn validate_session(cookies: &CookieJar) -> Option<JwtClaims> {
-    resolve_jwt::<JwtClaims>(&cookies).map(|x| x.claims)
+/// Lifetime of access tokens in seconds (1h)
+const ACCESS_TOKEN_LIFETIME: u64 = 60 * 60;
+/// Lifetime of refresh tokens in seconds (30d)
+const REFRESH_TOKEN_LIFETIME: u64 = 30 * 24 * 60 * 60;
+const REFRESH_TOKEN_COOKIE: &str = "refresh_token";
+/// Lifetime of tokens awaiting the second factor in seconds (5min)
+const PENDING_TOKEN_LIFETIME: u64 = 5 * 60;
+const PENDING_TOKEN_COOKIE: &str = "pending_token";
+
+/// Verified in place of the hash of unknown users
+static DUMMY_PASSWORD_HASH: Lazy<String> = Lazy::new(|| hash_password(""));
+
+/// Checks the JWT, and that its session hasn't been revoked; personal
+/// access tokens are also accepted within their scopes
+pub(crate) async fn validate_session(
+    cookies: &CookieJar,
+) -> database::Result<Result<JwtClaims, ResponseStatus>> {
+    if let Some(token) = cookies.get("token").filter(|x| is_api_token(x.value())) {
+        let Some(claims) = resolve_api_token(token.value()).await? else {
+            return Ok(Err(ResponseStatus::InvalidSession));
+        };
+        return Ok(check_scope(&claims).map(|_| claims));
+    }
+    let Some(claims) = resolve_jwt::<JwtClaims>(cookies).map(|x| x.claims) else {
+        return Ok(Err(ResponseStatus::InvalidSession));
+    };
+    let (session_id, user_id) = (claims.sid, claims.user_id);
+    let valid = database::read(move |db| Ok(db.check_session(session_id, user_id)?)).await?;
+    match valid {
+        true => Ok(Ok(claims)),
+        false => Ok(Err(ResponseStatus::InvalidSession)),
+    }
 }
 
 #[macro_export]
 macro_rules! get_session {
     ($cookies:expr) => {
-        match crate::routes::diary::session::validate_session($cookies) {
-            Some(claims) => claims,
-            None => {
+        match crate::routes::diary::session::validate_session($cookies).await {
+            Ok(Ok(claims)) => claims,
+            Ok(Err(status)) => {
                 return <_ as ::axum::response::IntoResponse>::into_response((
                     ::axum::http::StatusCode::FORBIDDEN,
-                    crate::routes::diary::failure_response(
-                        crate::routes::diary::ResponseStatus::InvalidSession,
-                    ),
+                    crate::routes::diary::failure_response(status),
                 ))
             }
+            Err(e) => return <_ as ::axum::response::IntoResponse>::into_response(e),
         }
     };
 }
 
+fn generate_refresh_token() -> String {
+    let mut token = [0_u8; 32];
+    OsRng.fill_bytes(&mut token);
+    token.encode_hex()
+}
+
+/// Refresh tokens are random enough, so a fast hash is sufficient
+fn hash_refresh_token(token: &str) -> String {
+    blake3::hash(token.as_bytes()).to_hex().to_string()
+}
+
+fn session_cookies(jwt: &str, refresh_token: &str) -> TypedHeader<SetCookie> {
+    set_cookies([
+        build_cookie("token", jwt, ACCESS_TOKEN_LIFETIME),
+        build_cookie(REFRESH_TOKEN_COOKIE, refresh_token, REFRESH_TOKEN_LIFETIME),
+    ])
+}
+
+/// Only replaces the access token; the refresh token is kept
+pub(crate) fn token_cookie(jwt: &str) -> TypedHeader<SetCookie> {
+    set_cookies([build_cookie("token", jwt, ACCESS_TOKEN_LIFETIME)])
+}
+
+pub(crate) fn cleared_cookies() -> TypedHeader<SetCookie> {
+    set_cookies([
+        build_cookie("token", "", 0),
+        build_cookie(REFRESH_TOKEN_COOKIE, "", 0),
+    ])
+}
+
+/// Claims of a login that passed the password check, but awaits the second
+/// factor. The user id field is named differently so that it can't be
+/// decoded as [`JwtClaims`], and vice versa.
+#[derive(Serialize, Deserialize)]
+struct PendingClaims {
+    pending_user_id: u64,
+    username: String,
+    iat: u64,
+    exp: u64,
+}
+
+fn pending_token_cookie(user_id: u64, username: String) -> TypedHeader<SetCookie> {
+    let timestamp = jsonwebtoken::get_current_timestamp();
+    let claims = PendingClaims {
+        pending_user_id: user_id,
+        username,
+        iat: timestamp,
+        exp: timestamp + PENDING_TOKEN_LIFETIME,
+    };
+    let jwt = encode_jwt(&claims);
+    set_cookies([build_cookie(
+        PENDING_TOKEN_COOKIE,
+        &jwt,
+        PENDING_TOKEN_LIFETIME,
+    )])
+}
+
+pub(crate) fn issue_jwt(user_id: u64, username: String, session_id: u64) -> (JwtClaims, String) {
+    let timestamp = jsonwebtoken::get_current_timestamp();
+    let claims = JwtClaims {
+        username,
+        user_id,
+        sid: session_id,
+        iat: timestamp,
+        exp: timestamp + ACCESS_TOKEN_LIFETIME,
+        api_token: None,
+        api_token_scope: None,
+    };
+    let jwt = encode_jwt(&claims);
+    (claims, jwt)
+}
+
+/// Verifies the password of a user, and transparently upgrades the
+/// stored hash if it's outdated
+///
+/// Returns: the user id if the password matches
+pub(crate) async fn authenticate(
+    username: String,
+    password: String,
+) -> database::Result<Option<u64>> {
+    check_password(move |db| db.query_credential(&username), password).await
+}
+
+/// Same as [`authenticate`], but looks up the user by id
+pub(crate) async fn authenticate_user_id(
+    user_id: u64,
+    password: String,
+) -> database::Result<Option<u64>> {
+    check_password(move |db| db.query_credential_by_id(user_id), password).await
+}
+
+async fn check_password<F>(query: F, password: String) -> database::Result<Option<u64>>
+where
+    F: FnOnce(&Database) -> rusqlite::Result<Option<Credential>> + Send + 'static,
+{
+    // the connection is released before the CPU intensive verification
+    let credential = database::read(move |db| Ok(query(db)?)).await?;
+    let user_id = credential.as_ref().map(|x| x.user_id);
+
+    let rehash = tokio::task::spawn_blocking(move || {
+        // unknown users are checked against a dummy hash, so that they
+        // can't be told apart by the response time
+        let (hash, salt) = match &credential {
+            Some(x) => (x.password_hash.as_str(), x.password_salt.as_bytes()),
+            None => (DUMMY_PASSWORD_HASH.as_str(), &b""[..]),
+        };
+        match verify_password(&password, hash, salt) {
+            PasswordVerification::Mismatch => Err(()),
+            PasswordVerification::Match => Ok(None),
+            PasswordVerification::NeedsRehash => Ok(Some(hash_password(&password))),
+        }
+    })
+    .await?;
+
+    let (Some(user_id), Ok(rehash)) = (user_id, rehash) else {
+        return Ok(None);
+    };
+    if let Some(new_hash) = rehash {
+        database::write(move |db| Ok(db.update_password_hash(user_id, &new_hash)?)).await?;
+    }
+    Ok(Some(user_id))
+}
+
 #[derive(Serialize)]
 pub struct ResponseData {
-    jwt: JwtClaims,
+    pub(crate) jwt: JwtClaims,
 }
 
 /// `#[serde(flatten)]` doesn't work now
@@ -52,8 +214,12 @@ pub struct LoginForm {
 pub struct CallbackExtras {
     succeeded: bool,
     username: Option<String>,
+    /// the login continues at `/session/2fa`
+    two_factor_required: bool,
 }
 
+/// Users with two-factor authentication get [`ResponseStatus::TwoFactorRequired`]
+/// and a pending token instead, see [`login_two_factor`]
 pub async fn login(Form(form): Form<LoginForm>) -> impl IntoResponse {
     fn response_html(callback_url: &str, extras: CallbackExtras) -> String {
         let json = serde_json::to_string(&extras).unwrap();
@@ -62,10 +228,12 @@ pub async fn login(Form(form): Form<LoginForm>) -> impl IntoResponse {
         redirect_html
     }
 
-    let database = lock_database!();
-    let valid = database.verify_password(&form.username, &form.password);
+    let user_id = match authenticate(form.username.clone(), form.password.clone()).await {
+        Ok(x) => x,
+        Err(e) => return e.into_response(),
+    };
 
-    if !valid {
+    let Some(user_id) = user_id else {
         return match form.callback {
             None => failure_response(ResponseStatus::AuthenticationFailed).into_response(),
             Some(c) => Html(response_html(
@@ -73,29 +241,57 @@ pub async fn login(Form(form): Form<LoginForm>) -> impl IntoResponse {
                 CallbackExtras {
                     succeeded: false,
                     username: None,
+                    two_factor_required: false,
                 },
             ))
             .into_response(),
         };
-    }
-
-    // unwrap: user must exists here
-    let user_id = database.query_user_id(&form.username).unwrap();
-    drop(database);
-    let timestamp = jsonwebtoken::get_current_timestamp();
-    let claims = JwtClaims {
-        username: form.username.clone(),
-        user_id,
-        iat: timestamp,
-        exp: timestamp + Duration::days(1).num_seconds() as u64,
     };
 
-    let jwt_header = jsonwebtoken::Header::new(Algorithm::HS512);
-    let key = EncodingKey::from_secret(&jwt_secret());
-    let jwt = jsonwebtoken::encode(&jwt_header, &claims, &key).unwrap();
+    let refresh_token = generate_refresh_token();
+    let refresh_token_hash = hash_refresh_token(&refresh_token);
+    let session_id = database::write(move |db| {
+        if db.is_user_disabled(user_id)? {
+            return Ok(Err(ResponseStatus::AccountDisabled));
+        }
+        if db.is_totp_enabled(user_id)? {
+            return Ok(Err(ResponseStatus::TwoFactorRequired));
+        }
+        let expiration_time = timestamp() + REFRESH_TOKEN_LIFETIME;
+        Ok(Ok(db.create_session(
+            user_id,
+            &refresh_token_hash,
+            expiration_time,
+        )?))
+    })
+    .await;
+    let session_id = match session_id {
+        Ok(Ok(x)) => x,
+        Ok(Err(status)) => {
+            let two_factor_required = status == ResponseStatus::TwoFactorRequired;
+            let header =
+                two_factor_required.then(|| pending_token_cookie(user_id, form.username.clone()));
+            return match form.callback {
+                None => (header, failure_response(status)).into_response(),
+                Some(c) => (
+                    header,
+                    Html(response_html(
+                        &c,
+                        CallbackExtras {
+                            succeeded: false,
+                            username: None,
+                            two_factor_required,
+                        },
+                    )),
+                )
+                    .into_response(),
+            };
+        }
+        Err(e) => return e.into_response(),
+    };
 
-    let set_cookies = [HeaderValue::from_str(&format!("token={}", jwt)).unwrap()];
-    let header = TypedHeader(SetCookie::decode(&mut set_cookies.iter()).unwrap());
+    let (claims, jwt) = issue_jwt(user_id, form.username.clone(), session_id);
+    let header = session_cookies(&jwt, &refresh_token);
 
     match form.callback {
         None => {
@@ -108,8 +304,139 @@ pub async fn login(Form(form): Form<LoginForm>) -> impl IntoResponse {
             let extras = CallbackExtras {
                 succeeded: true,
                 username: Some(claims.username),
+                two_factor_required: false,
             };
             (header, Html(response_html(&c, extras))).into_response()
         }
     }
 }
+
+/// Second step of logging in with two-factor authentication; exchanges the
+/// pending token of [`login`] and a TOTP or recovery code for a session
+pub async fn login_two_factor(cookies: CookieJar, Form(form): Form<CodeForm>) -> impl IntoResponse {
+    let Some(pending) = cookies
+        .get(PENDING_TOKEN_COOKIE)
+        .and_then(|x| decode_jwt::<PendingClaims>(x.value()))
+        .map(|x| x.claims)
+    else {
+        return (
+            StatusCode::FORBIDDEN,
+            failure_response(ResponseStatus::InvalidSession),
+        )
+            .into_response();
+    };
+    let user_id = pending.pending_user_id;
+    let now = timestamp();
+    if !TWO_FACTOR_LIMITER.check(user_id, now) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            failure_response(ResponseStatus::TooManyRequests),
+        )
+            .into_response();
+    }
+
+    let refresh_token = generate_refresh_token();
+    let refresh_token_hash = hash_refresh_token(&refresh_token);
+    let session_id = database::write(move |db| {
+        if db.is_user_disabled(user_id)? {
+            return Ok(Err(ResponseStatus::AccountDisabled));
+        }
+        if !verify_code(db, user_id, &form.code, now)? {
+            return Ok(Err(ResponseStatus::InvalidTwoFactorCode));
+        }
+        let expiration_time = now + REFRESH_TOKEN_LIFETIME;
+        Ok(Ok(db.create_session(
+            user_id,
+            &refresh_token_hash,
+            expiration_time,
+        )?))
+    })
+    .await;
+    let session_id = match session_id {
+        Ok(Ok(x)) => x,
+        Ok(Err(status)) => return failure_response(status).into_response(),
+        Err(e) => return e.into_response(),
+    };
+
+    let (claims, jwt) = issue_jwt(user_id, pending.username, session_id);
+    let header = set_cookies([
+        build_cookie("token", &jwt, ACCESS_TOKEN_LIFETIME),
+        build_cookie(REFRESH_TOKEN_COOKIE, &refresh_token, REFRESH_TOKEN_LIFETIME),
+        build_cookie(PENDING_TOKEN_COOKIE, "", 0),
+    ]);
+    (header, ResponseJson::ok(ResponseData { jwt: claims })).into_response()
+}
+
+/// Exchanges the refresh token for a new access token; the refresh
+/// token itself is also rotated
+pub async fn refresh(cookies: CookieJar) -> impl IntoResponse {
+    let Some(refresh_token) = cookies
+        .get(REFRESH_TOKEN_COOKIE)
+        .map(|x| x.value().to_string())
+    else {
+        return (
+            StatusCode::FORBIDDEN,
+            failure_response(ResponseStatus::InvalidSession),
+        )
+            .into_response();
+    };
+
+    let new_refresh_token = generate_refresh_token();
+    let new_refresh_token_hash = hash_refresh_token(&new_refresh_token);
+    let session = database::write(move |db| {
+        let hash = hash_refresh_token(&refresh_token);
+        let Some(session) = db.query_session_by_refresh_token(&hash)? else {
+            return Ok(None);
+        };
+        if session.expiration_time < timestamp() {
+            db.delete_session(session.id)?;
+            return Ok(None);
+        }
+        let expiration_time = timestamp() + REFRESH_TOKEN_LIFETIME;
+        db.rotate_refresh_token(session.id, &new_refresh_token_hash, expiration_time)?;
+        Ok(Some(session))
+    })
+    .await;
+
+    let session = match session {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            return (
+                StatusCode::FORBIDDEN,
+                failure_response(ResponseStatus::InvalidSession),
+            )
+                .into_response()
+        }
+        Err(e) => return e.into_response(),
+    };
+
+    let (claims, jwt) = issue_jwt(session.user_id, session.username, session.id);
+    let header = session_cookies(&jwt, &new_refresh_token);
+    (header, ResponseJson::ok(ResponseData { jwt: claims })).into_response()
+}
+
+/// Revokes the current session
+pub async fn logout(cookies: CookieJar) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+    require_login_session!(claims);
+
+    database::write(move |db| {
+        db.delete_session(claims.sid)?;
+        Ok((cleared_cookies(), ResponseJson::ok(())).into_response())
+    })
+    .await
+    .into_response()
+}
+
+/// Revokes all sessions of the current user, i.e. logs out all devices
+pub async fn logout_all(cookies: CookieJar) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+    require_login_session!(claims);
+
+    database::write(move |db| {
+        db.delete_user_sessions(claims.user_id)?;
+        Ok((cleared_cookies(), ResponseJson::ok(())).into_response())
+    })
+    .await
+    .into_response()
+}
@@ -0,0 +1,212 @@
+//! Sharing diary books between users
+//!
+//! The owner invites other users by username as editors or viewers; the
+//! invitation takes effect once the invitee accepts it.
+
+use axum::extract::Path;
+use axum::response::IntoResponse;
+use axum::Form;
+use axum_extra::extract::CookieJar;
+use serde::{Deserialize, Serialize};
+
+use crate::routes::diary::database;
+use crate::routes::diary::{failure_response, ResponseStatus};
+use crate::{get_session, ResponseJson};
+
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Owner,
+    /// can read and write entries
+    Editor,
+    /// can only read entries
+    Viewer,
+}
+
+impl Role {
+    pub fn from_db_int(role: u8) -> Role {
+        match role {
+            0 => Role::Owner,
+            1 => Role::Editor,
+            // the least privilege for unknown values
+            _ => Role::Viewer,
+        }
+    }
+
+    pub fn to_db_int(self) -> u8 {
+        match self {
+            Role::Owner => 0,
+            Role::Editor => 1,
+            Role::Viewer => 2,
+        }
+    }
+
+    /// Whether this role has at least the privileges of `required`
+    pub fn allows(self, required: Role) -> bool {
+        self.to_db_int() <= required.to_db_int()
+    }
+}
+
+/// Checks the role of the session user in a diary book
+///
+/// Non-members get [`ResponseStatus::NoRecord`], so that the existence of
+/// others' diary books isn't revealed.
+pub(crate) fn check_role(role: Option<Role>, required: Role) -> Result<(), ResponseStatus> {
+    match role {
+        None => Err(ResponseStatus::NoRecord),
+        Some(x) if !x.allows(required) => Err(ResponseStatus::PermissionDenied),
+        Some(_) => Ok(()),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Member {
+    pub user_id: u64,
+    pub username: String,
+    pub role: Role,
+    /// false for pending invitations
+    pub accepted: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Invitation {
+    pub book_id: u64,
+    pub book_name: String,
+    /// username of the owner
+    pub owner: String,
+    pub role: Role,
+}
+
+#[derive(Deserialize)]
+pub struct MemberForm {
+    /// either `editor` or `viewer`
+    pub role: Role,
+}
+
+/// Lists members of a diary book, including pending invitations
+pub async fn list_members(cookies: CookieJar, Path(book_id): Path<u64>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        if let Err(status) = check_role(db.query_book_role(book_id, claims.user_id)?, Role::Viewer)
+        {
+            return Ok(failure_response(status).into_response());
+        }
+
+        let members = db.list_book_members(book_id)?;
+        Ok(ResponseJson::ok(members).into_response())
+    })
+    .await
+    .into_response()
+}
+
+/// Invites a user to the diary book, or changes the role of a member;
+/// only the owner can do this
+pub async fn put_member(
+    cookies: CookieJar,
+    Path((book_id, username)): Path<(u64, String)>,
+    Form(form): Form<MemberForm>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    if form.role == Role::Owner {
+        return failure_response(ResponseStatus::PermissionDenied).into_response();
+    }
+
+    database::write(move |db| {
+        if let Err(status) = check_role(db.query_book_role(book_id, claims.user_id)?, Role::Owner) {
+            return Ok(failure_response(status).into_response());
+        }
+        // other users don't have the diary keys
+        if db.is_book_encrypted(book_id)? {
+            return Ok(failure_response(ResponseStatus::InvalidEncryption).into_response());
+        }
+        let Some(user_id) = db.query_user_id(&username)? else {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        };
+        if user_id == claims.user_id {
+            return Ok(failure_response(ResponseStatus::PermissionDenied).into_response());
+        }
+
+        db.put_book_member(book_id, user_id, form.role)?;
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
+}
+
+/// Revokes membership. The owner can remove anyone else; other members can
+/// only remove themselves, i.e. leave the book or decline the invitation.
+pub async fn delete_member(
+    cookies: CookieJar,
+    Path((book_id, username)): Path<(u64, String)>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::write(move |db| {
+        let Some(user_id) = db.query_user_id(&username)? else {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        };
+        let owner = db.query_book_owner(book_id)?;
+        if owner == Some(user_id) {
+            // the owner can only delete the book
+            return Ok(failure_response(ResponseStatus::PermissionDenied).into_response());
+        }
+        if user_id != claims.user_id && owner != Some(claims.user_id) {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        }
+
+        if !db.delete_book_member(book_id, user_id)? {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        }
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
+}
+
+/// Lists pending invitations of the current user
+pub async fn list_invitations(cookies: CookieJar) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        let invitations = db.list_invitations(claims.user_id)?;
+        Ok(ResponseJson::ok(invitations).into_response())
+    })
+    .await
+    .into_response()
+}
+
+pub async fn accept_invitation(cookies: CookieJar, Path(book_id): Path<u64>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::write(move |db| {
+        if !db.accept_invitation(book_id, claims.user_id)? {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        }
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
+}
+
+#[test]
+fn role_privileges() {
+    assert_eq!(check_role(Some(Role::Owner), Role::Editor), Ok(()));
+    assert_eq!(check_role(Some(Role::Editor), Role::Editor), Ok(()));
+    assert_eq!(
+        check_role(Some(Role::Viewer), Role::Editor),
+        Err(ResponseStatus::PermissionDenied)
+    );
+    assert_eq!(
+        check_role(Some(Role::Editor), Role::Owner),
+        Err(ResponseStatus::PermissionDenied)
+    );
+    assert_eq!(
+        check_role(None, Role::Viewer),
+        Err(ResponseStatus::NoRecord)
+    );
+    assert_eq!(Role::from_db_int(Role::Editor.to_db_int()), Role::Editor);
+}
@@ -0,0 +1,178 @@
+//! Registration controls: signup modes, invite codes, password strength
+//! and per-IP throttling of signup attempts
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+
+use axum::http::HeaderMap;
+use hex::ToHex;
+use once_cell::sync::Lazy;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::Serialize;
+
+use crate::{mutex_lock, SignupConfig, CONFIG};
+
+/// in characters
+const MAX_PASSWORD_LENGTH: usize = 256;
+/// Expired entries are pruned once this many keys are tracked; if none has
+/// expired, the one with the oldest window is evicted
+const MAX_TRACKED_KEYS: usize = 4096;
+
+pub(crate) static SIGNUP_CONFIG: Lazy<SignupConfig> = Lazy::new(|| {
+    let guard = mutex_lock!(CONFIG);
+    let config = guard.app.diary.as_ref().expect("Missing config");
+    config.signup.clone()
+});
+
+static SIGNUP_LIMITER: Lazy<RateLimiter<IpAddr>> =
+    Lazy::new(|| RateLimiter::new(SIGNUP_CONFIG.max_attempts, SIGNUP_CONFIG.attempt_window));
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InviteCode {
+    pub code: String,
+    /// username of the admin who generated it
+    pub created_by: Option<String>,
+    pub creation_time: u64,
+    pub expiration_time: Option<u64>,
+    /// username of the user who signed up with it
+    pub used_by: Option<String>,
+    pub used_time: Option<u64>,
+}
+
+/// Fixed-window counters of attempts per key, e.g. IP address
+pub(crate) struct RateLimiter<K> {
+    max_attempts: u32,
+    /// in seconds
+    window: u64,
+    /// window start time and attempt count
+    counters: Mutex<HashMap<K, (u64, u32)>>,
+}
+
+impl<K: Eq + Hash + Clone> RateLimiter<K> {
+    pub fn new(max_attempts: u32, window: u64) -> Self {
+        Self {
+            max_attempts,
+            window,
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records an attempt at `now` (in seconds)
+    ///
+    /// Returns: false if the key has run out of attempts
+    pub fn check(&self, key: K, now: u64) -> bool {
+        if self.max_attempts == 0 {
+            return true;
+        }
+        let mut counters = mutex_lock!(self.counters);
+        if counters.len() >= MAX_TRACKED_KEYS && !counters.contains_key(&key) {
+            counters.retain(|_, (start, _)| now.saturating_sub(*start) < self.window);
+            if counters.len() >= MAX_TRACKED_KEYS {
+                let oldest = counters
+                    .iter()
+                    .min_by_key(|(_, (start, _))| *start)
+                    .map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    counters.remove(&oldest);
+                }
+            }
+        }
+        let (start, count) = counters.entry(key).or_insert((now, 0));
+        if now.saturating_sub(*start) >= self.window {
+            (*start, *count) = (now, 0);
+        }
+        if *count >= self.max_attempts {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+/// Records a signup attempt of the client
+///
+/// Returns: false if the client should be throttled
+pub(crate) fn check_signup_rate(headers: &HeaderMap, peer: SocketAddr, now: u64) -> bool {
+    let header = SIGNUP_CONFIG
+        .ip_header
+        .as_ref()
+        .and_then(|x| headers.get(x))
+        .and_then(|x| x.to_str().ok());
+    SIGNUP_LIMITER.check(client_ip(header, peer.ip()), now)
+}
+
+/// `X-Forwarded-For` may contain a chain of addresses, where proxies append
+/// the address they received the request from. Only the last one is added
+/// by the reverse proxy in front of this server; the others are sent by the
+/// client, and can't be trusted.
+fn client_ip(header: Option<&str>, peer: IpAddr) -> IpAddr {
+    header
+        .and_then(|x| x.rsplit(',').next())
+        .and_then(|x| x.trim().parse().ok())
+        .unwrap_or(peer)
+}
+
+/// Requires `min_length` characters in at least two classes out of
+/// lowercase letters, uppercase letters, digits and others; the password
+/// must also differ from the username
+pub(crate) fn is_strong_password(password: &str, username: &str, min_length: usize) -> bool {
+    let length = password.chars().count();
+    if length < min_length.max(1)
+        || length > MAX_PASSWORD_LENGTH
+        || password.eq_ignore_ascii_case(username)
+    {
+        return false;
+    }
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    classes.into_iter().filter(|x| *x).count() >= 2
+}
+
+pub(crate) fn generate_invite_code() -> String {
+    let mut code = [0_u8; 8];
+    OsRng.fill_bytes(&mut code);
+    code.encode_hex()
+}
+
+#[test]
+fn signup_controls() {
+    assert!(is_strong_password("correct horse", "alice", 8));
+    assert!(is_strong_password("passw0rd", "alice", 8));
+    assert!(!is_strong_password("password", "alice", 8));
+    assert!(!is_strong_password("Pass1", "alice", 8));
+    assert!(!is_strong_password("Alice_1234", "alice_1234", 8));
+    assert!(!is_strong_password("", "alice", 0));
+
+    let limiter = RateLimiter::new(2, 60);
+    let ip = IpAddr::from([127, 0, 0, 1]);
+    assert!(limiter.check(ip, 0));
+    assert!(limiter.check(ip, 10));
+    assert!(!limiter.check(ip, 20));
+    assert!(limiter.check(IpAddr::from([127, 0, 0, 2]), 20));
+    assert!(limiter.check(ip, 60));
+
+    let limiter = RateLimiter::new(1, 1_000_000);
+    let now = MAX_TRACKED_KEYS as u64 + 10;
+    for key in 0..now {
+        assert!(limiter.check(key, key));
+    }
+    assert_eq!(mutex_lock!(limiter.counters).len(), MAX_TRACKED_KEYS);
+    // the oldest keys are evicted, while recent ones are still throttled
+    assert!(limiter.check(0, now));
+    assert!(!limiter.check(now - 1, now));
+    assert_eq!(mutex_lock!(limiter.counters).len(), MAX_TRACKED_KEYS);
+
+    let peer = IpAddr::from([10, 0, 0, 1]);
+    assert_eq!(client_ip(None, peer), peer);
+    assert_eq!(client_ip(Some("127.0.0.1"), peer), ip);
+    assert_eq!(client_ip(Some("1.2.3.4, 127.0.0.1"), peer), ip);
+    assert_eq!(client_ip(Some("127.0.0.1, unknown"), peer), peer);
+}
@@ -0,0 +1,196 @@
+//! Writing activity statistics of the current user
+
+use axum::extract::Query;
+use axum::response::IntoResponse;
+use axum_extra::extract::CookieJar;
+use chrono::{Datelike, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::routes::diary::database;
+use crate::{get_session, ResponseJson};
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    /// year of the calendar; defaults to the current year
+    pub year: Option<i32>,
+    /// counts entries in all accessible diary books if absent
+    pub book_id: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DayCount {
+    /// date integer, e.g. 20230415
+    pub date: u32,
+    pub count: u32,
+}
+
+/// Except the calendar, all numbers cover the whole history. Words and
+/// characters of encrypted entries aren't counted.
+#[derive(Serialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DiaryStats {
+    pub year: i32,
+    /// days with entries in `year`, in order
+    pub days: Vec<DayCount>,
+    /// entry counts of each month in `year`
+    pub months: [u32; 12],
+    /// consecutive days with entries up to today, or up to yesterday if
+    /// there's no entry today yet
+    pub current_streak: u32,
+    pub longest_streak: u32,
+    pub entry_count: u32,
+    pub word_count: u64,
+    pub character_count: u64,
+    /// entry counts of each weekday, from Monday to Sunday
+    pub weekdays: [u32; 7],
+}
+
+/// Collects statistics from entries visited in the order of their dates
+pub(crate) struct StatsBuilder {
+    stats: DiaryStats,
+    last_date: Option<NaiveDate>,
+    /// length of the streak ending at `last_date`
+    streak: u32,
+}
+
+impl StatsBuilder {
+    pub fn new(year: i32) -> Self {
+        Self {
+            stats: DiaryStats {
+                year,
+                days: Vec::new(),
+                months: [0; 12],
+                current_streak: 0,
+                longest_streak: 0,
+                entry_count: 0,
+                word_count: 0,
+                character_count: 0,
+                weekdays: [0; 7],
+            },
+            last_date: None,
+            streak: 0,
+        }
+    }
+
+    /// `content` is `None` for encrypted entries; invalid dates are skipped
+    pub fn add(&mut self, date: u32, content: Option<&str>) {
+        let Some(naive_date) =
+            NaiveDate::from_ymd_opt((date / 10000) as i32, date / 100 % 100, date % 100)
+        else {
+            return;
+        };
+        let stats = &mut self.stats;
+
+        stats.entry_count += 1;
+        stats.weekdays[naive_date.weekday().num_days_from_monday() as usize] += 1;
+        if let Some(content) = content {
+            stats.word_count += count_words(content);
+            stats.character_count += content.chars().count() as u64;
+        }
+        if naive_date.year() == stats.year {
+            stats.months[naive_date.month0() as usize] += 1;
+            match stats.days.last_mut() {
+                Some(x) if x.date == date => x.count += 1,
+                _ => stats.days.push(DayCount { date, count: 1 }),
+            }
+        }
+
+        match self.last_date {
+            Some(x) if x == naive_date => {}
+            Some(x) if x.succ_opt() == Some(naive_date) => self.streak += 1,
+            _ => self.streak = 1,
+        }
+        self.last_date = Some(naive_date);
+        stats.longest_streak = stats.longest_streak.max(self.streak);
+    }
+
+    pub fn finish(mut self, today: NaiveDate) -> DiaryStats {
+        self.stats.current_streak = match self.last_date {
+            Some(x) if x == today || x.succ_opt() == Some(today) => self.streak,
+            _ => 0,
+        };
+        self.stats
+    }
+}
+
+/// Counts whitespace-separated words; CJK characters are counted as
+/// one word each, as those scripts don't separate words with spaces
+fn count_words(text: &str) -> u64 {
+    let mut count = 0;
+    let mut in_word = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            in_word = false;
+        } else if is_cjk(c) {
+            count += 1;
+            in_word = false;
+        } else if !in_word {
+            count += 1;
+            in_word = true;
+        }
+    }
+    count
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}' // Hiragana and Katakana
+        | '\u{3400}'..='\u{4dbf}' // CJK Unified Ideographs Extension A
+        | '\u{4e00}'..='\u{9fff}' // CJK Unified Ideographs
+        | '\u{ac00}'..='\u{d7af}' // Hangul Syllables
+        | '\u{f900}'..='\u{faff}' // CJK Compatibility Ideographs
+        | '\u{20000}'..='\u{2fa1f}' // Supplementary Ideographic Plane
+    )
+}
+
+/// Returns the calendar heatmap, streaks and totals of the current user
+pub async fn stats(cookies: CookieJar, Query(query): Query<StatsQuery>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    let today = chrono::Local::now().date_naive();
+    let year = query.year.unwrap_or(today.year());
+    database::read(move |db| {
+        let mut builder = StatsBuilder::new(year);
+        db.visit_diaries(claims.user_id, query.book_id, |date, content| {
+            builder.add(date, content)
+        })?;
+        Ok(ResponseJson::ok(builder.finish(today)).into_response())
+    })
+    .await
+    .into_response()
+}
+
+#[test]
+fn activity_stats() {
+    assert_eq!(count_words("Hello,  world!\n foo"), 3);
+    assert_eq!(count_words("今天天气很好 ok"), 7);
+    assert_eq!(count_words(""), 0);
+
+    let mut builder = StatsBuilder::new(2023);
+    builder.add(20221231, Some("a b"));
+    builder.add(20230101, None);
+    builder.add(20230102, Some("c"));
+    builder.add(20230102, Some("d"));
+    builder.add(20230105, Some("e"));
+    builder.add(20230106, Some("f"));
+    let stats = builder.finish(NaiveDate::from_ymd_opt(2023, 1, 7).unwrap());
+
+    let days = stats
+        .days
+        .iter()
+        .map(|x| (x.date, x.count))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        days,
+        [(20230101, 1), (20230102, 2), (20230105, 1), (20230106, 1)]
+    );
+    assert_eq!(stats.months[0], 5);
+    assert_eq!(stats.longest_streak, 3);
+    assert_eq!(stats.current_streak, 2);
+    assert_eq!(stats.entry_count, 6);
+    assert_eq!(stats.word_count, 6);
+    assert_eq!(stats.character_count, 7);
+    // 2023-01-02 is a Monday
+    assert_eq!(stats.weekdays, [2, 0, 0, 1, 1, 1, 1]);
+}
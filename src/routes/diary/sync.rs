@@ -0,0 +1,241 @@
+//! Delta sync of diary books with offline clients
+//!
+//! Each change of an entry increments its version and takes a number from a
+//! server-wide counter. Clients send the counter value returned by their last
+//! sync (the cursor) along with their local changes, and receive the server
+//! changes after it. A local change is only applied if it's based on the
+//! current server version of its date; otherwise the server version wins and
+//! the change is reported as a conflict. Deleted entries leave tombstones so
+//! that deletions are synced too.
+
+use axum::response::IntoResponse;
+use axum::Json;
+use axum_extra::extract::CookieJar;
+use serde::{Deserialize, Serialize};
+
+use crate::routes::diary::database;
+use crate::routes::diary::database::MetadataUpdate;
+use crate::routes::diary::diary_entry::{check_encryption, metadata_update, DiaryEntry};
+use crate::routes::diary::encryption::Encryption;
+use crate::routes::diary::sharing::{check_role, Role};
+use crate::routes::diary::{attachment, failure_response, is_valid_date, ResponseStatus};
+use crate::{get_session, ResponseJson};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncRequest {
+    pub book_id: u64,
+    /// `cursor` of the last sync response; 0 fetches all entries
+    #[serde(default)]
+    pub cursor: u64,
+    #[serde(default)]
+    pub changes: Vec<LocalChange>,
+}
+
+/// Full state of an entry changed by the client
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalChange {
+    /// date integer, e.g. 20230415
+    pub date: u32,
+    /// server version the change is based on, which may be the version of a
+    /// tombstone; 0 if the client has never seen an entry of the date
+    #[serde(default)]
+    pub base_version: u64,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub content: String,
+    pub encryption: Option<Encryption>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub mood: Option<u8>,
+    pub location: Option<String>,
+}
+
+/// Validated [`LocalChange`]
+pub(crate) struct SyncChange {
+    pub date: u32,
+    pub base_version: u64,
+    /// `None` for deletions
+    pub content: Option<String>,
+    pub encryption: Option<Encryption>,
+    pub metadata: MetadataUpdate,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tombstone {
+    pub date: u32,
+    pub version: u64,
+    pub deletion_time: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppliedChange {
+    pub date: u32,
+    /// id of the entry; absent for deletions
+    pub id: Option<u64>,
+    pub version: u64,
+}
+
+/// A local change discarded in favor of the server version
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncConflict {
+    pub date: u32,
+    pub base_version: u64,
+    pub server_version: u64,
+    /// absent if the entry is deleted or has never existed on the server
+    pub entry: Option<DiaryEntry>,
+}
+
+pub(crate) enum SyncOutcome {
+    Applied(AppliedChange),
+    Conflict(SyncConflict),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncResponse {
+    /// to be sent in the next sync
+    pub cursor: u64,
+    /// entries changed by others after the request cursor, ordered by date
+    pub entries: Vec<DiaryEntry>,
+    /// entries deleted by others after the request cursor, ordered by date
+    pub deleted: Vec<Tombstone>,
+    /// in the order of local changes
+    pub applied: Vec<AppliedChange>,
+    /// in the order of local changes
+    pub conflicts: Vec<SyncConflict>,
+}
+
+/// Server state of a date in a diary book
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum SlotState {
+    Missing,
+    Live { id: u64, version: u64 },
+    Deleted { version: u64 },
+}
+
+impl SlotState {
+    pub fn version(self) -> u64 {
+        match self {
+            SlotState::Missing => 0,
+            SlotState::Live { version, .. } | SlotState::Deleted { version } => version,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Resolution {
+    Write,
+    /// deletes the entry of the id
+    Delete(u64),
+    /// the entry is already absent
+    Unchanged,
+    Conflict,
+}
+
+/// Decides what to do with a local change based on `base_version`
+pub(crate) fn resolve(base_version: u64, deleted: bool, slot: SlotState) -> Resolution {
+    if base_version != slot.version() {
+        return Resolution::Conflict;
+    }
+    match (deleted, slot) {
+        (false, _) => Resolution::Write,
+        (true, SlotState::Live { id, .. }) => Resolution::Delete(id),
+        (true, _) => Resolution::Unchanged,
+    }
+}
+
+/// Applies local changes and returns server changes since the cursor;
+/// pulling only requires the viewer role
+pub async fn sync(cookies: CookieJar, Json(request): Json<SyncRequest>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    let mut changes = Vec::with_capacity(request.changes.len());
+    for change in request.changes {
+        if !is_valid_date(change.date) {
+            return failure_response(ResponseStatus::InvalidDate).into_response();
+        }
+        let Some(metadata) = metadata_update(
+            Some(change.tags.as_slice()),
+            Some(change.mood),
+            Some(change.location),
+        ) else {
+            return failure_response(ResponseStatus::InvalidMetadata).into_response();
+        };
+        changes.push(SyncChange {
+            date: change.date,
+            base_version: change.base_version,
+            content: (!change.deleted).then_some(change.content),
+            encryption: change.encryption,
+            metadata,
+        });
+    }
+    let (book_id, cursor) = (request.book_id, request.cursor);
+
+    database::write(move |db| {
+        let role = match changes.is_empty() {
+            true => Role::Viewer,
+            false => Role::Editor,
+        };
+        if let Err(status) = check_role(db.query_book_role(book_id, claims.user_id)?, role) {
+            return Ok(failure_response(status).into_response());
+        }
+        for change in changes.iter().filter(|x| x.content.is_some()) {
+            if !check_encryption(db, book_id, claims.user_id, change.encryption.as_ref())? {
+                return Ok(failure_response(ResponseStatus::InvalidEncryption).into_response());
+            }
+        }
+
+        let last_seq = db.query_change_seq()?;
+        // the client is ahead if the server has been restored from a backup
+        let cursor = if cursor > last_seq { 0 } else { cursor };
+        let outcomes = db.sync_diaries(book_id, &changes)?;
+        if changes.iter().any(|x| x.content.is_none()) {
+            attachment::remove_orphan_files(db)?;
+        }
+
+        let mut applied = Vec::new();
+        let mut conflicts = Vec::new();
+        for outcome in outcomes {
+            match outcome {
+                SyncOutcome::Applied(x) => applied.push(x),
+                SyncOutcome::Conflict(x) => conflicts.push(x),
+            }
+        }
+        // changes made by this sync have later numbers, and are left out
+        let response = SyncResponse {
+            cursor: db.query_change_seq()?,
+            entries: db.list_changed_diaries(book_id, cursor, last_seq)?,
+            deleted: db.list_tombstones(book_id, cursor, last_seq)?,
+            applied,
+            conflicts,
+        };
+        Ok(ResponseJson::ok(response).into_response())
+    })
+    .await
+    .into_response()
+}
+
+#[test]
+fn conflict_resolution() {
+    let live = SlotState::Live { id: 7, version: 2 };
+    let deleted = SlotState::Deleted { version: 3 };
+
+    assert_eq!(resolve(0, false, SlotState::Missing), Resolution::Write);
+    assert_eq!(resolve(0, true, SlotState::Missing), Resolution::Unchanged);
+    assert_eq!(resolve(1, false, SlotState::Missing), Resolution::Conflict);
+
+    assert_eq!(resolve(2, false, live), Resolution::Write);
+    assert_eq!(resolve(2, true, live), Resolution::Delete(7));
+    assert_eq!(resolve(1, false, live), Resolution::Conflict);
+    assert_eq!(resolve(0, false, live), Resolution::Conflict);
+
+    assert_eq!(resolve(3, false, deleted), Resolution::Write);
+    assert_eq!(resolve(3, true, deleted), Resolution::Unchanged);
+    assert_eq!(resolve(2, false, deleted), Resolution::Conflict);
+}
@@ -0,0 +1,76 @@
+use std::collections::HashSet;
+
+use axum::extract::Query;
+use axum::response::IntoResponse;
+use axum_extra::extract::CookieJar;
+use serde::{Deserialize, Serialize};
+
+use crate::routes::diary::database;
+use crate::{get_session, ResponseJson};
+
+/// in characters
+const MAX_TAG_LENGTH: usize = 32;
+const MAX_TAG_COUNT: usize = 16;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagCount {
+    pub tag: String,
+    pub count: u32,
+}
+
+#[derive(Deserialize)]
+pub struct TagCloudQuery {
+    /// counts tags in all accessible diary books if absent
+    pub book_id: Option<u64>,
+}
+
+/// Trims tags and removes duplicates
+///
+/// Returns: `None` if there are empty or too long tags, or too many tags
+pub(crate) fn normalize_tags(tags: &[String]) -> Option<Vec<String>> {
+    let mut seen = HashSet::new();
+    let mut result = Vec::new();
+    for tag in tags {
+        let tag = tag.trim();
+        if tag.is_empty() || tag.chars().count() > MAX_TAG_LENGTH {
+            return None;
+        }
+        if seen.insert(tag) {
+            result.push(tag.to_string());
+        }
+    }
+    (result.len() <= MAX_TAG_COUNT).then_some(result)
+}
+
+/// Returns entry counts per tag of the current user
+pub async fn tag_cloud(
+    cookies: CookieJar,
+    Query(query): Query<TagCloudQuery>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        let counts = db.count_tags(claims.user_id, query.book_id)?;
+        Ok(ResponseJson::ok(counts).into_response())
+    })
+    .await
+    .into_response()
+}
+
+#[test]
+fn tag_normalization() {
+    let tags = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+    assert_eq!(
+        normalize_tags(&tags(&[" travel", "旅行", "travel "])),
+        Some(tags(&["travel", "旅行"]))
+    );
+    assert_eq!(normalize_tags(&[]), Some(vec![]));
+    assert_eq!(normalize_tags(&tags(&["a", " "])), None);
+    assert_eq!(normalize_tags(&tags(&[&"a".repeat(33)])), None);
+    assert_eq!(
+        normalize_tags(&vec![String::from("a"); 17]),
+        Some(tags(&["a"]))
+    );
+}
@@ -0,0 +1,305 @@
+//! Opt-in two-factor authentication with TOTP (RFC 6238)
+//!
+//! Users enroll by adding the returned `otpauth` URI to an authenticator app,
+//! and confirming it with a first code, which also yields single-use recovery
+//! codes. After that, logging in takes two steps: the password step only
+//! issues a short-lived pending token, which is exchanged for a session with
+//! a valid code at `/session/2fa`.
+
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Form;
+use axum_extra::extract::CookieJar;
+use hex::ToHex;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::routes::diary::api_token::require_login_session;
+use crate::routes::diary::database;
+use crate::routes::diary::database::Database;
+use crate::routes::diary::session::authenticate_user_id;
+use crate::routes::diary::signup::RateLimiter;
+use crate::routes::diary::{failure_response, timestamp, ResponseStatus};
+use crate::{get_session, ResponseJson};
+
+/// in bytes, as recommended by RFC 4226
+const SECRET_LENGTH: usize = 20;
+/// in seconds
+const TIME_STEP: u64 = 30;
+const CODE_DIGITS: u32 = 6;
+/// Codes of adjacent time steps are also accepted to tolerate clock drift
+const ALLOWED_DRIFT: u64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const ISSUER: &str = "Diary";
+/// Max code attempts per user in `ATTEMPT_WINDOW`
+const MAX_ATTEMPTS: u32 = 5;
+/// in seconds
+const ATTEMPT_WINDOW: u64 = 5 * 60;
+
+pub(crate) static TWO_FACTOR_LIMITER: Lazy<RateLimiter<u64>> =
+    Lazy::new(|| RateLimiter::new(MAX_ATTEMPTS, ATTEMPT_WINDOW));
+
+pub(crate) struct TotpSecret {
+    /// hex-encoded
+    pub secret: String,
+    /// false until the enrollment is confirmed
+    pub enabled: bool,
+    /// time step of the last accepted code
+    pub last_step: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    pub recovery_codes_left: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Enrollment {
+    /// base32-encoded, for entering manually
+    pub secret: String,
+    /// `otpauth://` URI, usually shown as a QR code
+    pub uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct CodeForm {
+    /// TOTP code, or a recovery code when logging in
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct DisableForm {
+    pub password: String,
+}
+
+/// HOTP value of the counter (RFC 4226)
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let bytes = [
+        hash[offset],
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ];
+    (u32::from_be_bytes(bytes) & 0x7fff_ffff) % 10_u32.pow(CODE_DIGITS)
+}
+
+/// Codes of time steps not later than `last_step` are rejected as replays
+///
+/// Returns: the time step of the matched code
+fn match_totp(secret: &[u8], code: &str, now: u64, last_step: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != CODE_DIGITS as usize || !code.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = now / TIME_STEP;
+    (current.saturating_sub(ALLOWED_DRIFT)..=current + ALLOWED_DRIFT)
+        .filter(|x| *x > last_step)
+        .find(|x| hotp(secret, *x) == code)
+}
+
+/// RFC 4648 base32 without padding, as used by authenticator apps
+fn base32_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut result = String::new();
+    for chunk in data.chunks(5) {
+        let mut block = [0_u8; 8];
+        block[3..3 + chunk.len()].copy_from_slice(chunk);
+        let bits = u64::from_be_bytes(block);
+        for i in 0..(chunk.len() * 8).div_ceil(5) {
+            result.push(ALPHABET[(bits >> (35 - i * 5)) as usize & 31] as char);
+        }
+    }
+    result
+}
+
+fn otpauth_uri(username: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        urlencoding::encode(&format!("{}:{}", ISSUER, username)),
+        secret,
+        urlencoding::encode(ISSUER),
+        CODE_DIGITS,
+        TIME_STEP
+    )
+}
+
+/// Codes in the form of `xxxxx-xxxxx`, in lowercase hex
+fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut code = [0_u8; 5];
+            OsRng.fill_bytes(&mut code);
+            let code: String = code.encode_hex();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Recovery codes are random enough, so a fast hash is sufficient. Case,
+/// dashes and spaces are ignored.
+pub(crate) fn hash_recovery_code(code: &str) -> String {
+    let normalized = code
+        .chars()
+        .filter(|x| x.is_ascii_alphanumeric())
+        .map(|x| x.to_ascii_lowercase())
+        .collect::<String>();
+    blake3::hash(normalized.as_bytes()).to_hex().to_string()
+}
+
+/// Checks a TOTP code or a recovery code of a user with two-factor
+/// authentication enabled; accepted codes can't be used again
+pub(crate) fn verify_code(
+    db: &Database,
+    user_id: u64,
+    code: &str,
+    now: u64,
+) -> rusqlite::Result<bool> {
+    let Some(totp) = db.query_totp(user_id)?.filter(|x| x.enabled) else {
+        return Ok(false);
+    };
+    let Ok(secret) = hex::decode(&totp.secret) else {
+        return Ok(false);
+    };
+    match match_totp(&secret, code, now, totp.last_step) {
+        Some(step) => db.update_totp_step(user_id, step),
+        None => db.use_recovery_code(user_id, &hash_recovery_code(code)),
+    }
+}
+
+pub async fn status(cookies: CookieJar) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    database::read(move |db| {
+        let status = TwoFactorStatus {
+            enabled: db.is_totp_enabled(claims.user_id)?,
+            recovery_codes_left: db.count_recovery_codes(claims.user_id)?,
+        };
+        Ok(ResponseJson::ok(status).into_response())
+    })
+    .await
+    .into_response()
+}
+
+/// Generates a new secret; it replaces any unconfirmed one, and takes effect
+/// after [`confirm`]
+pub async fn enroll(cookies: CookieJar) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+    require_login_session!(claims);
+
+    let mut secret = [0_u8; SECRET_LENGTH];
+    OsRng.fill_bytes(&mut secret);
+    database::write(move |db| {
+        if db.is_totp_enabled(claims.user_id)? {
+            return Ok(failure_response(ResponseStatus::TwoFactorEnabled).into_response());
+        }
+
+        db.put_totp_secret(claims.user_id, &secret.encode_hex::<String>())?;
+        let encoded = base32_encode(&secret);
+        let enrollment = Enrollment {
+            uri: otpauth_uri(&claims.username, &encoded),
+            secret: encoded,
+        };
+        Ok(ResponseJson::ok(enrollment).into_response())
+    })
+    .await
+    .into_response()
+}
+
+/// Enables two-factor authentication with a first code from the
+/// authenticator
+///
+/// Returns: recovery codes, which are only shown this once
+pub async fn confirm(cookies: CookieJar, Form(form): Form<CodeForm>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+    require_login_session!(claims);
+
+    let now = timestamp();
+    if !TWO_FACTOR_LIMITER.check(claims.user_id, now) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            failure_response(ResponseStatus::TooManyRequests),
+        )
+            .into_response();
+    }
+    let recovery_codes = generate_recovery_codes();
+    database::write(move |db| {
+        let Some(totp) = db.query_totp(claims.user_id)?.filter(|x| !x.enabled) else {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        };
+        let Ok(secret) = hex::decode(&totp.secret) else {
+            return Ok(failure_response(ResponseStatus::NoRecord).into_response());
+        };
+        let Some(step) = match_totp(&secret, &form.code, now, 0) else {
+            return Ok(failure_response(ResponseStatus::InvalidTwoFactorCode).into_response());
+        };
+
+        let hashes = recovery_codes
+            .iter()
+            .map(|x| hash_recovery_code(x))
+            .collect::<Vec<_>>();
+        db.enable_totp(claims.user_id, step, &hashes)?;
+        Ok(ResponseJson::ok(recovery_codes).into_response())
+    })
+    .await
+    .into_response()
+}
+
+/// Disables two-factor authentication; the password is required for
+/// confirmation
+pub async fn disable(cookies: CookieJar, Form(form): Form<DisableForm>) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+    require_login_session!(claims);
+
+    match authenticate_user_id(claims.user_id, form.password).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return failure_response(ResponseStatus::AuthenticationFailed).into_response(),
+        Err(e) => return e.into_response(),
+    }
+
+    database::write(move |db| {
+        db.delete_totp(claims.user_id)?;
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
+}
+
+#[test]
+fn totp_codes() {
+    // test vectors of RFC 6238, truncated to six digits
+    let secret = b"12345678901234567890";
+    assert_eq!(hotp(secret, 59 / TIME_STEP), 287082);
+    assert_eq!(hotp(secret, 1111111109 / TIME_STEP), 81804);
+    assert_eq!(hotp(secret, 1234567890 / TIME_STEP), 5924);
+    assert_eq!(base32_encode(secret), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    assert_eq!(base32_encode(b"f"), "MY");
+    assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+
+    let step = 1234567890 / TIME_STEP;
+    assert_eq!(match_totp(secret, " 005924 ", 1234567890, 0), Some(step));
+    assert_eq!(match_totp(secret, "005924", 1234567890 + 30, 0), Some(step));
+    assert_eq!(match_totp(secret, "005924", 1234567890 + 60, 0), None);
+    // replayed
+    assert_eq!(match_totp(secret, "005924", 1234567890, step), None);
+    assert_eq!(match_totp(secret, "5924", 1234567890, 0), None);
+
+    assert_eq!(
+        hash_recovery_code("AbCdE-12345"),
+        hash_recovery_code("abcde 12345")
+    );
+    let codes = generate_recovery_codes();
+    assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+    assert!(codes.iter().all(|x| x.len() == 11));
+}
@@ -1,15 +1,25 @@
-use axum::extract::Path;
-use axum::http::StatusCode;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+
+use axum::extract::{ConnectInfo, Path};
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::IntoResponse;
 use axum::{Form, Json};
 use axum_extra::extract::CookieJar;
 use serde::{Deserialize, Serialize};
 
-use crate::routes::diary::session::validate_session;
+use crate::routes::diary::api_token::require_login_session;
+use crate::routes::diary::database;
+use crate::routes::diary::etag::{check_if_match, entity_tag, etag_header};
+use crate::routes::diary::session::{
+    authenticate_user_id, cleared_cookies, issue_jwt, token_cookie, ResponseData,
+};
+use crate::routes::diary::signup::{check_signup_rate, is_strong_password, SIGNUP_CONFIG};
 use crate::routes::diary::{
-    failure_response, generate_password_hash, AuthForm, JwtClaims, ResponseStatus,
+    attachment, failure_response, generate_password_hash, nullable, timestamp, AuthForm,
+    ResponseStatus,
 };
-use crate::{get_session, lock_database, ResponseJson};
+use crate::{get_session, ResponseJson, SignupMode};
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -21,6 +31,34 @@ pub struct UserProfile {
     pub gender: Gender,
 }
 
+const USERNAME_LENGTH: RangeInclusive<usize> = 3..=32;
+const MAX_EMAIL_LENGTH: usize = 254;
+
+/// Payload of profile updates; absent fields are left unchanged, and
+/// `null` clears optional fields
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileUpdate {
+    pub username: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub email: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub name: Option<Option<String>>,
+    pub gender: Option<Gender>,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordForm {
+    /// the current password
+    pub password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteForm {
+    pub password: String,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "tag", content = "value")]
 pub enum Gender {
@@ -51,48 +89,257 @@ impl Gender {
     }
 }
 
-pub async fn user_info(Path(username): Path<String>) -> impl IntoResponse {
-    let database = lock_database!();
+/// Usernames consist of ASCII letters, digits, `_`, `-` and `.`, and
+/// start with a letter or digit
+pub(crate) fn is_valid_username(username: &str) -> bool {
+    USERNAME_LENGTH.contains(&username.len())
+        && username.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
 
-    let result: Option<UserProfile> = try {
-        let user_id = database.query_user_id(&username)?;
-        database.query_user_profile(user_id)?
-    };
-    match result {
-        Some(a) => ResponseJson::ok(a).into_response(),
-        None => failure_response(ResponseStatus::NoRecord).into_response(),
+/// A loose syntax check: `local@domain`, where the domain has at least one dot
+fn is_valid_email(email: &str) -> bool {
+    if email.len() > MAX_EMAIL_LENGTH || email.contains(char::is_whitespace) {
+        return false;
     }
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && !domain.contains('@')
+        && domain
+            .split('.')
+            .all(|x| !x.is_empty() && !x.starts_with('-') && !x.ends_with('-'))
+        && domain.contains('.')
+}
+
+pub async fn user_info(Path(username): Path<String>) -> impl IntoResponse {
+    database::read(move |db| {
+        let result: Option<UserProfile> = match db.query_user_id(&username)? {
+            None => None,
+            Some(user_id) => db.query_user_profile(user_id)?,
+        };
+        Ok(match result {
+            Some(a) => (etag_header(entity_tag(&a)), ResponseJson::ok(a)).into_response(),
+            None => failure_response(ResponseStatus::NoRecord).into_response(),
+        })
+    })
+    .await
+    .into_response()
 }
 
 pub async fn me_user_info(cookies: CookieJar) -> impl IntoResponse {
     let c = get_session!(&cookies);
 
-    let database = lock_database!();
-    match database.query_user_profile(c.user_id) {
-        None => failure_response(ResponseStatus::NoRecord).into_response(),
-        Some(x) => ResponseJson::ok(x).into_response(),
+    database::read(move |db| {
+        Ok(match db.query_user_profile(c.user_id)? {
+            None => failure_response(ResponseStatus::NoRecord).into_response(),
+            Some(x) => (etag_header(entity_tag(&x)), ResponseJson::ok(x)).into_response(),
+        })
+    })
+    .await
+    .into_response()
+}
+
+/// Signs up; depending on the signup mode, an invite code may be required.
+/// Attempts are throttled per IP address.
+pub async fn create_user(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Form(form): Form<AuthForm>,
+) -> impl IntoResponse {
+    let config = &*SIGNUP_CONFIG;
+    if config.mode == SignupMode::Closed {
+        return failure_response(ResponseStatus::SignupDisabled).into_response();
+    }
+    if !check_signup_rate(&headers, peer, timestamp()) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            failure_response(ResponseStatus::TooManyRequests),
+        )
+            .into_response();
     }
+    if !is_valid_username(&form.username) {
+        return failure_response(ResponseStatus::InvalidUsername).into_response();
+    }
+    if !is_strong_password(&form.password, &form.username, config.min_password_length) {
+        return failure_response(ResponseStatus::WeakPassword).into_response();
+    }
+    let invite_code = match (config.mode, form.invite_code) {
+        (SignupMode::Invite, None) => {
+            return failure_response(ResponseStatus::InvalidInviteCode).into_response()
+        }
+        (SignupMode::Invite, x) => x,
+        _ => None,
+    };
+
+    let pw_hash = generate_password_hash(form.password).await;
+
+    database::write(move |db| {
+        if db.check_existence(&form.username)? {
+            // user exists
+            return Ok(failure_response(ResponseStatus::UserExists).into_response());
+        }
+
+        match invite_code {
+            None => {
+                db.add_user(&form.username, &pw_hash)?;
+            }
+            Some(code) => {
+                if !db.add_invited_user(&form.username, &pw_hash, &code)? {
+                    return Ok(failure_response(ResponseStatus::InvalidInviteCode).into_response());
+                }
+            }
+        }
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
 }
 
-pub async fn create_user(Form(form): Form<AuthForm>) -> impl IntoResponse {
-    let (pw_hash, salt) = generate_password_hash(&form.password);
-    let database = lock_database!();
+/// Partially updates the profile. A new access token is issued if the
+/// username of a login session changes, since it's carried in the JWT.
+pub async fn update_user(
+    cookies: CookieJar,
+    headers: HeaderMap,
+    Json(form): Json<ProfileUpdate>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+
+    if let Some(username) = &form.username {
+        if !is_valid_username(username) {
+            return failure_response(ResponseStatus::InvalidUsername).into_response();
+        }
+    }
+    if let Some(Some(email)) = &form.email {
+        if !is_valid_email(email) {
+            return failure_response(ResponseStatus::InvalidEmail).into_response();
+        }
+    }
+
+    let user_id = claims.user_id;
+    let result = database::write(move |db| {
+        let Some(mut profile) = db.query_user_profile(user_id)? else {
+            return Ok(Err(
+                failure_response(ResponseStatus::NoRecord).into_response()
+            ));
+        };
+        if let Some(x) = check_if_match(&headers, Some(&entity_tag(&profile))) {
+            return Ok(Err(x));
+        }
+        if let Some(username) = form.username {
+            if username != profile.username && db.check_existence(&username)? {
+                return Ok(Err(
+                    failure_response(ResponseStatus::UserExists).into_response()
+                ));
+            }
+            profile.username = username;
+        }
+        if let Some(email) = form.email {
+            profile.email = email;
+        }
+        if let Some(name) = form.name {
+            profile.name = name;
+        }
+        if let Some(gender) = form.gender {
+            profile.gender = gender;
+        }
+
+        db.update_user_profile(user_id, &profile)?;
+        Ok(Ok((entity_tag(&profile), profile.username)))
+    })
+    .await;
 
-    if database.check_existence(&form.username) {
-        // user exists
-        return failure_response(ResponseStatus::UserExists).into_response();
+    match result {
+        // personal access tokens keep working, as they aren't JWTs
+        Ok(Ok((tag, username))) if username != claims.username && claims.api_token.is_none() => {
+            let (claims, jwt) = issue_jwt(claims.user_id, username, claims.sid);
+            (
+                etag_header(tag),
+                token_cookie(&jwt),
+                ResponseJson::ok(ResponseData { jwt: claims }),
+            )
+                .into_response()
+        }
+        Ok(Ok((tag, _))) => (etag_header(tag), ResponseJson::ok(())).into_response(),
+        Ok(Err(response)) => response,
+        Err(e) => e.into_response(),
     }
+}
 
-    database.add_user(&form.username, &pw_hash, &salt);
+/// Changes the password; all other sessions of the user are revoked
+pub async fn change_password(
+    cookies: CookieJar,
+    Form(form): Form<PasswordForm>,
+) -> impl IntoResponse {
+    let claims = get_session!(&cookies);
+    require_login_session!(claims);
+
+    let min_length = SIGNUP_CONFIG.min_password_length;
+    if !is_strong_password(&form.new_password, &claims.username, min_length) {
+        return failure_response(ResponseStatus::WeakPassword).into_response();
+    }
+    match authenticate_user_id(claims.user_id, form.password).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return failure_response(ResponseStatus::AuthenticationFailed).into_response(),
+        Err(e) => return e.into_response(),
+    }
+    let pw_hash = generate_password_hash(form.new_password).await;
 
-    ResponseJson::ok(()).into_response()
+    database::write(move |db| {
+        db.update_password_hash(claims.user_id, &pw_hash)?;
+        db.delete_other_sessions(claims.user_id, claims.sid)?;
+        Ok(ResponseJson::ok(()).into_response())
+    })
+    .await
+    .into_response()
 }
 
-pub async fn update_user(cookies: CookieJar, Json(form): Json<UserProfile>) -> impl IntoResponse {
+/// Deletes the current user and all their data; the password is required
+/// for confirmation
+pub async fn delete_user(
+    cookies: CookieJar,
+    headers: HeaderMap,
+    Form(form): Form<DeleteForm>,
+) -> impl IntoResponse {
     let claims = get_session!(&cookies);
+    require_login_session!(claims);
+
+    match authenticate_user_id(claims.user_id, form.password).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return failure_response(ResponseStatus::AuthenticationFailed).into_response(),
+        Err(e) => return e.into_response(),
+    }
+
+    database::write(move |db| {
+        let current = db.query_user_profile(claims.user_id)?;
+        if let Some(x) = check_if_match(&headers, current.as_ref().map(entity_tag).as_deref()) {
+            return Ok(x);
+        }
+
+        db.delete_user(claims.user_id)?;
+        attachment::remove_orphan_files(db)?;
+        Ok((cleared_cookies(), ResponseJson::ok(())).into_response())
+    })
+    .await
+    .into_response()
+}
 
-    let database = lock_database!();
+#[test]
+fn profile_validation() {
+    assert!(is_valid_username("bczhc"));
+    assert!(is_valid_username("a.b-c_1"));
+    assert!(!is_valid_username("ab"));
+    assert!(!is_valid_username("_abc"));
+    assert!(!is_valid_username("a b c"));
+    assert!(!is_valid_username(&"a".repeat(33)));
 
-    database.update_user_profile(claims.user_id, &form);
-    ResponseJson::ok(()).into_response()
+    assert!(is_valid_email("a@example.com"));
+    assert!(!is_valid_email("a@example"));
+    assert!(!is_valid_email("@example.com"));
+    assert!(!is_valid_email("a@@example.com"));
+    assert!(!is_valid_email("a b@example.com"));
+    assert!(!is_valid_email("a@example..com"));
 }
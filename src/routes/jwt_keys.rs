@@ -0,0 +1,35 @@
+use axum::routing::get;
+use axum::Router;
+use serde::Serialize;
+
+use crate::security::JWT_KEYS;
+use crate::{mutex_lock, ResponseJson};
+
+#[derive(Serialize)]
+pub struct PublicKey {
+    kid: String,
+    alg: jsonwebtoken::Algorithm,
+    pem: String,
+}
+
+/// Lists public keys of asymmetric JWT keys, so other services
+/// can verify tokens issued here
+pub async fn public_keys() -> ResponseJson<Vec<PublicKey>> {
+    let guard = mutex_lock!(JWT_KEYS);
+    let keys = guard
+        .iter()
+        .flat_map(|x| &x.keys)
+        .filter_map(|x| {
+            Some(PublicKey {
+                kid: x.id.clone()?,
+                alg: x.algorithm,
+                pem: x.public_key.clone()?,
+            })
+        })
+        .collect();
+    ResponseJson::ok(keys)
+}
+
+pub fn router() -> Router {
+    Router::new().route("/", get(public_keys))
+}
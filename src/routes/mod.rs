@@ -4,6 +4,7 @@ pub mod app;
 pub mod ccit_info;
 pub mod demo;
 pub mod diary;
+pub mod jwt_keys;
 pub mod random;
 pub mod server_network_log;
 pub mod system_info;
@@ -1,50 +1,353 @@
-use axum_extra::extract::CookieJar;
+use std::fs;
 use std::sync::Mutex;
 
+use anyhow::anyhow;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier};
+use axum::headers::{Header as _, HeaderValue, SetCookie};
+use axum::TypedHeader;
+use axum_extra::extract::CookieJar;
 use hex::ToHex;
-use jsonwebtoken::{DecodingKey, TokenData, Validation};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation};
 use once_cell::sync::Lazy;
 use rand::rngs::OsRng;
 use rand::RngCore;
 use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{
+    lazy_option_initializer, mutex_lock, print_flush, JwtKeyConfig, LazyOption, SameSite, CONFIG,
+    DEFAULT_JWT_ISSUER,
+};
+
+/// Default allowed clock skew in seconds
+const DEFAULT_JWT_LEEWAY: u64 = 60;
+
+pub static JWT_KEYS: LazyOption<JwtKeys> = lazy_option_initializer!();
+
+pub struct JwtKey {
+    /// `kid` in JWT headers; `None` for the ephemeral key
+    pub id: Option<String>,
+    pub algorithm: Algorithm,
+    /// only present for keys that can sign tokens
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+    /// PEM-encoded public key of asymmetric keys
+    pub public_key: Option<String>,
+}
+
+pub struct JwtKeys {
+    pub keys: Vec<JwtKey>,
+    /// index of the key used for signing new tokens
+    signing: usize,
+    issuer: String,
+    audience: String,
+    leeway: u64,
+}
+
+impl JwtKey {
+    fn ephemeral() -> Self {
+        let mut secret = [0_u8; 300];
+        OsRng.fill_bytes(&mut secret);
+        Self {
+            id: None,
+            algorithm: Algorithm::HS512,
+            encoding: Some(EncodingKey::from_secret(&secret)),
+            decoding: DecodingKey::from_secret(&secret),
+            public_key: None,
+        }
+    }
+
+    fn load(config: &JwtKeyConfig) -> anyhow::Result<Self> {
+        let read_file = |path: &Option<String>, name: &str| {
+            let path = path
+                .as_ref()
+                .ok_or_else(|| anyhow!("Missing {} for JWT key {}", name, config.id))?;
+            anyhow::Ok(fs::read(path)?)
+        };
+
+        let (encoding, decoding, public_key) = match config.algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secret = read_file(&config.secret_file, "secret-file")?;
+                (
+                    Some(EncodingKey::from_secret(&secret)),
+                    DecodingKey::from_secret(&secret),
+                    None,
+                )
+            }
+            algorithm => {
+                let public_pem = read_file(&config.public_key_file, "public-key-file")?;
+                let private_pem = match config.private_key_file {
+                    None => None,
+                    Some(_) => Some(read_file(&config.private_key_file, "private-key-file")?),
+                };
+                let (encoding, decoding) = match algorithm {
+                    Algorithm::RS256
+                    | Algorithm::RS384
+                    | Algorithm::RS512
+                    | Algorithm::PS256
+                    | Algorithm::PS384
+                    | Algorithm::PS512 => (
+                        private_pem
+                            .map(|x| EncodingKey::from_rsa_pem(&x))
+                            .transpose()?,
+                        DecodingKey::from_rsa_pem(&public_pem)?,
+                    ),
+                    Algorithm::ES256 | Algorithm::ES384 => (
+                        private_pem
+                            .map(|x| EncodingKey::from_ec_pem(&x))
+                            .transpose()?,
+                        DecodingKey::from_ec_pem(&public_pem)?,
+                    ),
+                    _ => (
+                        private_pem
+                            .map(|x| EncodingKey::from_ed_pem(&x))
+                            .transpose()?,
+                        DecodingKey::from_ed_pem(&public_pem)?,
+                    ),
+                };
+                (encoding, decoding, Some(String::from_utf8(public_pem)?))
+            }
+        };
 
-use crate::routes::demo::authentication::jwt_secret;
-use crate::{lazy_option_initializer, mutex_lock, print_flush, LazyOption};
+        Ok(Self {
+            id: Some(config.id.clone()),
+            algorithm: config.algorithm,
+            encoding,
+            decoding,
+            public_key,
+        })
+    }
+}
+
+impl JwtKeys {
+    fn load() -> anyhow::Result<Self> {
+        let guard = mutex_lock!(CONFIG);
+        let config = guard.jwt.as_ref();
+        let issuer = config
+            .and_then(|x| x.issuer.clone())
+            .unwrap_or_else(|| String::from(DEFAULT_JWT_ISSUER));
+        let audience = config
+            .and_then(|x| x.audience.clone())
+            .unwrap_or_else(|| String::from(DEFAULT_JWT_ISSUER));
+        let leeway = config.and_then(|x| x.leeway).unwrap_or(DEFAULT_JWT_LEEWAY);
+
+        let key_configs = config.map(|x| &x.keys[..]).unwrap_or_default();
+        if key_configs.is_empty() {
+            // without configured keys, sessions are invalidated by restarts
+            print_flush!("Generating JWT secret... ");
+            let keys = Self {
+                keys: vec![JwtKey::ephemeral()],
+                signing: 0,
+                issuer,
+                audience,
+                leeway,
+            };
+            println!("done");
+            return Ok(keys);
+        }
+
+        let keys = key_configs
+            .iter()
+            .map(JwtKey::load)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let signing_key = config
+            .and_then(|x| x.signing_key.as_ref())
+            .ok_or_else(|| anyhow!("Missing signing-key in JWT config"))?;
+        let signing = keys
+            .iter()
+            .position(|x| x.id.as_ref() == Some(signing_key))
+            .ok_or_else(|| anyhow!("Signing JWT key not found: {}", signing_key))?;
+        if keys[signing].encoding.is_none() {
+            return Err(anyhow!(
+                "Signing JWT key has no private key: {}",
+                signing_key
+            ));
+        }
+        println!("Loaded {} JWT key(s)", keys.len());
+        Ok(Self {
+            keys,
+            signing,
+            issuer,
+            audience,
+            leeway,
+        })
+    }
+
+    fn find(&self, kid: Option<&str>) -> Option<&JwtKey> {
+        self.keys.iter().find(|x| x.id.as_deref() == kid)
+    }
+
+    fn validation(&self, key: &JwtKey) -> Validation {
+        // the algorithm is bound to the key rather than taken from the token
+        let mut validation = Validation::new(key.algorithm);
+        validation.leeway = self.leeway;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        validation
+    }
+}
+
+/// Claims with `iss` and `aud` added
+#[derive(Serialize)]
+struct RegisteredClaims<'a, C> {
+    #[serde(flatten)]
+    claims: &'a C,
+    iss: &'a str,
+    aud: &'a str,
+}
 
-pub static JWT_SECRET: LazyOption<[u8; 300]> = lazy_option_initializer!();
+pub fn init() -> anyhow::Result<()> {
+    let keys = JwtKeys::load()?;
+    mutex_lock!(JWT_KEYS).replace(keys);
+    Ok(())
+}
+
+/// Signs `claims` with the current signing key
+pub fn encode_jwt<C: Serialize>(claims: &C) -> String {
+    let guard = mutex_lock!(JWT_KEYS);
+    let keys = guard.as_ref().expect("JWT keys not initialized");
+    let key = &keys.keys[keys.signing];
 
-pub fn init() {
-    print_flush!("Generating JWT secret... ");
-    let mut secret = [0_u8; 300];
-    OsRng.fill_bytes(&mut secret);
-    mutex_lock!(JWT_SECRET).replace(secret);
-    println!("done");
+    let mut header = Header::new(key.algorithm);
+    header.kid = key.id.clone();
+    let claims = RegisteredClaims {
+        claims,
+        iss: &keys.issuer,
+        aud: &keys.audience,
+    };
+    // unwrap: the signing key always has an encoding key
+    jsonwebtoken::encode(&header, &claims, key.encoding.as_ref().unwrap()).unwrap()
 }
 
-pub fn hash_password(pw: &str, salt: &[u8]) -> String {
+/// Builds a `Set-Cookie` value, with attributes from the cookie config
+pub fn build_cookie(name: &str, value: &str, max_age: u64) -> HeaderValue {
+    let guard = mutex_lock!(CONFIG);
+    let config = &guard.cookie;
+
+    let mut cookie = format!(
+        "{}={}; Path={}; Max-Age={}",
+        name, value, config.path, max_age
+    );
+    if let Some(domain) = &config.domain {
+        cookie.push_str(&format!("; Domain={}", domain));
+    }
+    if config.http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    if config.secure {
+        cookie.push_str("; Secure");
+    }
+    cookie.push_str(match config.same_site {
+        SameSite::Strict => "; SameSite=Strict",
+        SameSite::Lax => "; SameSite=Lax",
+        SameSite::None => "; SameSite=None",
+    });
+    HeaderValue::from_str(&cookie).unwrap()
+}
+
+/// Builds a `Set-Cookie` header; `cookies` are values from [`build_cookie`]
+pub fn set_cookies<const N: usize>(cookies: [HeaderValue; N]) -> TypedHeader<SetCookie> {
+    TypedHeader(SetCookie::decode(&mut cookies.iter()).unwrap())
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PasswordVerification {
+    Mismatch,
+    Match,
+    /// The password matches, but the stored hash is in a legacy format
+    /// or uses outdated parameters, and should be replaced.
+    NeedsRehash,
+}
+
+/// Hashes a password with Argon2id
+///
+/// Returns: a PHC string containing the algorithm, parameters and salt
+pub fn hash_password(pw: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(pw.as_bytes(), &salt)
+        .expect("Password hashing failed")
+        .to_string()
+}
+
+/// Unsalted-iteration BLAKE3 hash used before Argon2 was introduced
+fn hash_password_legacy(pw: &str, salt: &[u8]) -> String {
     let mut hasher = blake3::Hasher::new();
     hasher.update(pw.as_bytes());
     hasher.update(salt);
     hasher.finalize().as_bytes().encode_hex()
 }
 
+/// Verifies a password against a stored hash
+///
+/// `legacy_salt` is only used when `hash` is a legacy BLAKE3 hash.
+pub fn verify_password(pw: &str, hash: &str, legacy_salt: &[u8]) -> PasswordVerification {
+    let Ok(parsed) = PasswordHash::new(hash) else {
+        return if hash_password_legacy(pw, legacy_salt) == hash {
+            PasswordVerification::NeedsRehash
+        } else {
+            PasswordVerification::Mismatch
+        };
+    };
+
+    if Argon2::default()
+        .verify_password(pw.as_bytes(), &parsed)
+        .is_err()
+    {
+        return PasswordVerification::Mismatch;
+    }
+
+    let current = Params::default();
+    let outdated = parsed.algorithm != argon2::Algorithm::Argon2id.ident()
+        || !Params::try_from(&parsed).is_ok_and(|p| {
+            (p.m_cost(), p.t_cost(), p.p_cost())
+                == (current.m_cost(), current.t_cost(), current.p_cost())
+        });
+    if outdated {
+        PasswordVerification::NeedsRehash
+    } else {
+        PasswordVerification::Match
+    }
+}
+
 pub fn resolve_jwt<C: DeserializeOwned>(cookies: &CookieJar) -> Option<TokenData<C>> {
     let Some(token) = cookies.get("token").map(|x| x.value()) else {
         return None;
     };
+    decode_jwt(token)
+}
 
-    let jwt_secret = jwt_secret();
+pub fn decode_jwt<C: DeserializeOwned>(token: &str) -> Option<TokenData<C>> {
     let Ok(header) = jsonwebtoken::decode_header(token) else {
         return None;
     };
-    let result = jsonwebtoken::decode::<C>(
-        token,
-        &DecodingKey::from_secret(&jwt_secret),
-        &Validation::new(header.alg.clone()),
+    let guard = mutex_lock!(JWT_KEYS);
+    let keys = guard.as_ref()?;
+    let key = keys.find(header.kid.as_deref())?;
+    jsonwebtoken::decode::<C>(token, &key.decoding, &keys.validation(key)).ok()
+}
+
+#[test]
+fn password_verification() {
+    let hash = hash_password("123");
+    assert_eq!(
+        verify_password("123", &hash, b""),
+        PasswordVerification::Match
+    );
+    assert_eq!(
+        verify_password("1234", &hash, b""),
+        PasswordVerification::Mismatch
     );
-    let Ok(claims) = result else {
-        return None;
-    };
 
-    Some(claims)
+    let legacy = hash_password_legacy("123", b"salt");
+    assert_eq!(
+        verify_password("123", &legacy, b"salt"),
+        PasswordVerification::NeedsRehash
+    );
+    assert_eq!(
+        verify_password("1234", &legacy, b"salt"),
+        PasswordVerification::Mismatch
+    );
 }